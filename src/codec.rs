@@ -0,0 +1,420 @@
+use anyhow::bail;
+use gst::prelude::ObjectExt;
+use serde::Deserialize;
+use tracing::warn;
+
+pub const TWCC_EXTENSION_ID: u32 = 1;
+pub const TWCC_EXTENSION_URI: &str =
+    "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+/// Profile the H264 encoder output is constrained to, as x264enc's caps name it.
+/// Every H264 decoder handles it.
+pub const H264_PROFILE: &str = "constrained-baseline";
+pub const RID_EXTENSION_ID: u32 = 2;
+pub const RID_EXTENSION_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    H264,
+    Vp8,
+    Vp9,
+}
+
+impl VideoCodec {
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            Self::H264 => "H264",
+            Self::Vp8 => "VP8",
+            Self::Vp9 => "VP9",
+        }
+    }
+
+    /// Inverse of `encoding_name`, ignoring case.
+    pub fn from_encoding_name(name: &str) -> Option<Self> {
+        [Self::H264, Self::Vp8, Self::Vp9]
+            .iter()
+            .copied()
+            .find(|codec| codec.encoding_name().eq_ignore_ascii_case(name))
+    }
+
+    /// Encoder segment of the launch string, the element is named `name`.
+    /// `key_int_max` is the most frames between two keyframes, `x264` only
+    /// applies to H264.
+    pub fn encoder(
+        &self,
+        name: &str,
+        bitrate_kbps: u32,
+        key_int_max: u32,
+        x264: &X264Config,
+    ) -> String {
+        match self {
+            Self::H264 => format!(
+                "x264enc name={} bitrate={}{} key-int-max={} ! 
+                video/x-h264,profile={}",
+                name,
+                bitrate_kbps,
+                x264.properties(),
+                key_int_max,
+                H264_PROFILE
+            ),
+            Self::Vp8 => format!(
+                "vp8enc name={} target-bitrate={} error-resilient=partitions keyframe-max-dist={} auto-alt-ref=true cpu-used=5 deadline=1",
+                name, bitrate_kbps * 1000, key_int_max
+            ),
+            Self::Vp9 => format!(
+                "vp9enc name={} target-bitrate={} error-resilient=default keyframe-max-dist={} cpu-used=5 deadline=1",
+                name, bitrate_kbps * 1000, key_int_max
+            ),
+        }
+    }
+
+    /// Payloader segment of the launch string, the element is named `name`.
+    ///
+    /// `extensions` are `(id, uri)` RTP header extensions the payloader adds
+    /// to every packet, which needs GStreamer 1.20.
+    pub fn payloader(&self, name: &str, payload: u32, extensions: &[(u32, &str)]) -> String {
+        let payloader = match self {
+            Self::H264 => "rtph264pay config-interval=-1",
+            Self::Vp8 => "rtpvp8pay",
+            Self::Vp9 => "rtpvp9pay",
+        };
+        let extmap = extensions
+            .iter()
+            .map(|(id, uri)| format!(",extmap-{}=(string)\"{}\"", id, uri))
+            .collect::<String>();
+        format!(
+            "{} name={} pt={} ! application/x-rtp,media=video,encoding-name={},payload={}{}",
+            payloader,
+            name,
+            payload,
+            self.encoding_name(),
+            payload,
+            extmap
+        )
+    }
+
+    /// Parser and muxer for writing the encoded stream to a file.
+    pub fn muxer(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264parse ! mp4mux",
+            Self::Vp8 | Self::Vp9 => "matroskamux",
+        }
+    }
+
+    /// Depayloader and decoder for an incoming stream of this codec.
+    pub fn decoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "rtph264depay ! avdec_h264",
+            Self::Vp8 => "rtpvp8depay ! vp8dec",
+            Self::Vp9 => "rtpvp9depay ! vp9dec",
+        }
+    }
+
+    /// x264enc takes kbps in `bitrate`, the libvpx encoders bps in `target-bitrate`.
+    pub fn set_bitrate(&self, encoder: &gst::Element, kbps: u32) -> Result<(), anyhow::Error> {
+        match self {
+            Self::H264 => encoder.set_property("bitrate", &kbps)?,
+            Self::Vp8 | Self::Vp9 => encoder.set_property("target-bitrate", &((kbps * 1000) as i32))?,
+        }
+        Ok(())
+    }
+
+    /// The libvpx encoders pick up a new `keyframe-max-dist` while playing,
+    /// x264enc only reads `key-int-max` when it starts.
+    pub fn set_key_int_max(
+        &self,
+        encoder: &gst::Element,
+        frames: u32,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Self::H264 => bail!("x264enc can't change key-int-max while playing"),
+            Self::Vp8 | Self::Vp9 => encoder.set_property("keyframe-max-dist", &(frames as i32))?,
+        }
+        Ok(())
+    }
+}
+
+/// x264enc `speed-preset`, slower presets spend more CPU on better quality
+/// at the same bitrate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderPreset {
+    UltraFast,
+    Fast,
+    Medium,
+    Slow,
+}
+
+impl EncoderPreset {
+    fn nick(&self) -> &'static str {
+        match self {
+            Self::UltraFast => "ultrafast",
+            Self::Fast => "fast",
+            Self::Medium => "medium",
+            Self::Slow => "slow",
+        }
+    }
+}
+
+/// x264enc `tune`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderTune {
+    /// No frame reordering or lookahead, what live streaming needs.
+    ZeroLatency,
+    FastDecode,
+    StillImage,
+}
+
+impl EncoderTune {
+    fn nick(&self) -> &'static str {
+        match self {
+            Self::ZeroLatency => "zerolatency",
+            Self::FastDecode => "fastdecode",
+            Self::StillImage => "stillimage",
+        }
+    }
+}
+
+/// Speed against quality trade-off of the H264 encoder.
+///
+/// ```toml
+/// x264 = { preset = "medium", tune = "zero_latency" }
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct X264Config {
+    pub preset: EncoderPreset,
+    /// x264's own defaults when unset, which buffer frames and add latency.
+    pub tune: Option<EncoderTune>,
+}
+
+impl Default for X264Config {
+    fn default() -> Self {
+        Self {
+            preset: EncoderPreset::UltraFast,
+            tune: Some(EncoderTune::ZeroLatency),
+        }
+    }
+}
+
+impl X264Config {
+    /// Every combination is accepted, the ones that likely can't keep up
+    /// live are only warned about.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.preset == EncoderPreset::Slow && self.tune == Some(EncoderTune::ZeroLatency) {
+            warn!("the slow x264 preset may not encode in real time, even tuned for zerolatency");
+        }
+        if self.tune != Some(EncoderTune::ZeroLatency) {
+            warn!(tune = ?self.tune, "x264 isn't tuned for zerolatency, expect added delay");
+        }
+
+        Ok(())
+    }
+
+    /// ` speed-preset=... tune=...` for the x264enc segment.
+    fn properties(&self) -> String {
+        let mut properties = format!(" speed-preset={}", self.preset.nick());
+        if let Some(tune) = self.tune {
+            properties.push_str(&format!(" tune={}", tune.nick()));
+        }
+        properties
+    }
+}
+
+/// x264enc's name for the profile of an SDP `profile-level-id`, e.g. `42e01f`.
+pub fn h264_profile(profile_level_id: &str) -> Option<&'static str> {
+    if profile_level_id.len() != 6 {
+        return None;
+    }
+    let profile_idc = u8::from_str_radix(&profile_level_id[0..2], 16).ok()?;
+    let constraints = u8::from_str_radix(&profile_level_id[2..4], 16).ok()?;
+
+    match profile_idc {
+        // constraint_set1_flag marks the constrained subset of baseline
+        0x42 if constraints & 0x40 != 0 => Some("constrained-baseline"),
+        0x42 => Some("baseline"),
+        0x4d => Some("main"),
+        0x58 => Some("extended"),
+        0x64 => Some("high"),
+        _ => None,
+    }
+}
+
+/// The offered `profile-level-id` that suits the encoder's `H264_PROFILE`
+/// output best. Constrained baseline is a subset of baseline, main and high,
+/// so those are taken in that order when it isn't offered itself.
+pub fn compatible_h264_profile_level_id(offered: &[String]) -> Option<&str> {
+    [H264_PROFILE, "baseline", "main", "high"]
+        .iter()
+        .find_map(|&profile| offered.iter().find(|id| h264_profile(id) == Some(profile)))
+        .map(String::as_str)
+}
+
+/// `opusenc` settings for the audio track.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpusConfig {
+    /// Target bitrate in bps.
+    pub bitrate: u32,
+    /// In-band forward error correction, only kicks in when
+    /// `packet_loss_percentage` is above zero.
+    pub inband_fec: bool,
+    /// Discontinuous transmission, sends next to nothing during silence.
+    pub dtx: bool,
+    /// Expected packet loss the encoder budgets FEC for.
+    pub packet_loss_percentage: u32,
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        Self {
+            bitrate: 64000,
+            inband_fec: true,
+            dtx: false,
+            packet_loss_percentage: 10,
+        }
+    }
+}
+
+impl OpusConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !(6000..=510000).contains(&self.bitrate) {
+            bail!("opus bitrate {} must be within 6000-510000 bps", self.bitrate);
+        }
+        if self.packet_loss_percentage > 100 {
+            bail!(
+                "packet loss percentage {} must be at most 100",
+                self.packet_loss_percentage
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Encoder segment of the launch string, the element is named `audio_encoder`.
+    pub fn encoder(&self) -> String {
+        format!(
+            "opusenc name=audio_encoder bitrate={} inband-fec={} dtx={} packet-loss-percentage={}",
+            self.bitrate, self.inband_fec, self.dtx, self.packet_loss_percentage
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn h264_profiles() {
+        assert_eq!(h264_profile("42e01f"), Some("constrained-baseline"));
+        assert_eq!(h264_profile("42001f"), Some("baseline"));
+        assert_eq!(h264_profile("4d001f"), Some("main"));
+        assert_eq!(h264_profile("640032"), Some("high"));
+        assert_eq!(h264_profile("f4001f"), None);
+        assert_eq!(h264_profile("42e0"), None);
+    }
+
+    #[test]
+    fn compatible_profile_prefers_constrained_baseline() {
+        let offered = ids(&["640032", "42001f", "42e01f", "4d001f"]);
+        assert_eq!(compatible_h264_profile_level_id(&offered), Some("42e01f"));
+
+        let offered = ids(&["640032", "4d001f", "42001f"]);
+        assert_eq!(compatible_h264_profile_level_id(&offered), Some("42001f"));
+
+        let offered = ids(&["640032", "4d001f"]);
+        assert_eq!(compatible_h264_profile_level_id(&offered), Some("4d001f"));
+
+        assert_eq!(compatible_h264_profile_level_id(&ids(&["f4001f"])), None);
+        assert_eq!(compatible_h264_profile_level_id(&[]), None);
+    }
+
+    #[test]
+    fn encoder_per_codec() {
+        let x264 = X264Config::default();
+        assert_eq!(
+            VideoCodec::H264
+                .encoder("encoder", 800, 30, &x264)
+                .split_whitespace()
+                .collect::<Vec<_>>(),
+            vec![
+                "x264enc",
+                "name=encoder",
+                "bitrate=800",
+                "speed-preset=ultrafast",
+                "tune=zerolatency",
+                "key-int-max=30",
+                "!",
+                "video/x-h264,profile=constrained-baseline",
+            ]
+        );
+
+        // libvpx takes the bitrate in bps
+        let vp8 = VideoCodec::Vp8.encoder("encoder", 800, 30, &x264);
+        assert!(vp8.starts_with("vp8enc name=encoder target-bitrate=800000 "));
+        assert!(vp8.contains(" keyframe-max-dist=30 "));
+        let vp9 = VideoCodec::Vp9.encoder("encoder_low", 200, 15, &x264);
+        assert!(vp9.starts_with("vp9enc name=encoder_low target-bitrate=200000 "));
+        assert!(vp9.contains(" keyframe-max-dist=15 "));
+        assert!(!vp9.contains("speed-preset"));
+    }
+
+    #[test]
+    fn encoding_names() {
+        for codec in [VideoCodec::H264, VideoCodec::Vp8, VideoCodec::Vp9] {
+            assert_eq!(VideoCodec::from_encoding_name(codec.encoding_name()), Some(codec));
+            let lowercase = codec.encoding_name().to_ascii_lowercase();
+            assert_eq!(VideoCodec::from_encoding_name(&lowercase), Some(codec));
+        }
+        assert_eq!(VideoCodec::from_encoding_name("AV1"), None);
+    }
+
+    #[test]
+    fn x264_properties() {
+        let x264 = X264Config {
+            preset: EncoderPreset::Medium,
+            tune: Some(EncoderTune::FastDecode),
+        };
+        assert_eq!(x264.properties(), " speed-preset=medium tune=fastdecode");
+        assert!(VideoCodec::H264
+            .encoder("encoder", 800, 30, &x264)
+            .contains("bitrate=800 speed-preset=medium tune=fastdecode key-int-max=30"));
+
+        let x264 = X264Config {
+            preset: EncoderPreset::Slow,
+            tune: None,
+        };
+        assert_eq!(x264.properties(), " speed-preset=slow");
+
+        // Latency-unfriendly combinations are only warned about
+        assert!(x264.validate().is_ok());
+        let x264 = X264Config {
+            tune: Some(EncoderTune::ZeroLatency),
+            ..x264
+        };
+        assert!(x264.validate().is_ok());
+    }
+
+    #[test]
+    fn opus_bitrate_range() {
+        assert!(OpusConfig::default().validate().is_ok());
+        for (bitrate, valid) in [(5999, false), (6000, true), (510000, true), (510001, false)] {
+            let opus = OpusConfig {
+                bitrate,
+                ..Default::default()
+            };
+            assert_eq!(opus.validate().is_ok(), valid, "{}", bitrate);
+        }
+
+        let opus = OpusConfig {
+            packet_loss_percentage: 101,
+            ..Default::default()
+        };
+        assert!(opus.validate().is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bastion::supervisor::RestartStrategy;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    codec::{OpusConfig, VideoCodec, X264Config},
+    dtls::DtlsConfig,
+    ice::{IceConfig, IceMode, TurnServer},
+    stats::QualityThresholds,
+    turn::TimeLimitedTurn,
+    webrtcbin_actor::{
+        LatencyConfig, PipelineConfig, RecordingConfig, RtcpMuxPolicy, TransceiverMode,
+        VideoConfig, VideoSource, WebRTCBinActor,
+    },
+};
+
+/// Deployment settings read from a TOML file, every key is optional.
+///
+/// ```toml
+/// video_codec = "h264"
+/// bitrate_kbps = 600
+/// video_source = { type = "v4l2", device = "/dev/video0" }
+/// audio = true
+/// opus = { bitrate = 32000, inband_fec = true, dtx = true }
+/// stun_servers = ["stun:stun.example.com:3478"]
+///
+/// [[turn_servers]]
+/// url = "turn:turn.example.com:3478?transport=tcp"
+/// username = "user"
+/// credential = "secret"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub video_codec: VideoCodec,
+    pub codec_preference: Vec<String>,
+    pub video_source: VideoSource,
+    pub restart_source_on_eos: bool,
+    pub bitrate_kbps: u32,
+    pub max_bitrate_kbps: Option<u32>,
+    pub key_int_max: u32,
+    pub x264: X264Config,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: Option<u32>,
+    pub transceiver_mode: TransceiverMode,
+    pub incoming_video_sink: String,
+    pub composite_incoming: bool,
+    pub video_queue_leaky: bool,
+    pub video_queue_max_time_ms: u64,
+    pub latency: LatencyConfig,
+    pub audio: bool,
+    pub opus: OpusConfig,
+    pub bandwidth_estimation: bool,
+    pub quality_thresholds: QualityThresholds,
+    pub enable_rtx: bool,
+    pub simulcast: bool,
+    pub rtcp_mux: RtcpMuxPolicy,
+    pub ice_mode: IceMode,
+    pub stun_servers: Vec<String>,
+    pub turn_servers: Vec<TurnServer>,
+    pub time_limited_turn: Option<TimeLimitedTurn>,
+    pub allow_insecure_turn: bool,
+    pub recording: Option<RecordingConfig>,
+    pub local_preview: bool,
+    pub sdp_dump_dir: Option<PathBuf>,
+    pub dtls: DtlsConfig,
+    /// How often a failed pipeline actor is restarted before giving up.
+    pub max_restarts: usize,
+    /// Address for the Prometheus `/metrics` endpoint, disabled when unset.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let pipeline = PipelineConfig::default();
+        Self {
+            video_codec: pipeline.video_codec,
+            codec_preference: pipeline.codec_preference,
+            video_source: pipeline.video_source,
+            restart_source_on_eos: pipeline.restart_source_on_eos,
+            bitrate_kbps: pipeline.bitrate_kbps,
+            max_bitrate_kbps: pipeline.max_bitrate_kbps,
+            key_int_max: pipeline.key_int_max,
+            x264: pipeline.x264,
+            width: pipeline.video.width,
+            height: pipeline.video.height,
+            framerate: pipeline.video.framerate,
+            transceiver_mode: pipeline.transceiver_mode,
+            incoming_video_sink: pipeline.incoming_video_sink,
+            composite_incoming: pipeline.composite_incoming,
+            video_queue_leaky: pipeline.video_queue_leaky,
+            video_queue_max_time_ms: pipeline.video_queue_max_time_ms,
+            latency: pipeline.latency,
+            audio: pipeline.audio,
+            opus: pipeline.opus,
+            bandwidth_estimation: pipeline.bandwidth_estimation,
+            quality_thresholds: pipeline.quality_thresholds,
+            enable_rtx: pipeline.enable_rtx,
+            simulcast: pipeline.simulcast,
+            rtcp_mux: pipeline.rtcp_mux,
+            ice_mode: pipeline.ice.mode,
+            stun_servers: pipeline.ice.stun_servers,
+            turn_servers: pipeline.ice.turn_servers,
+            time_limited_turn: pipeline.ice.time_limited_turn,
+            allow_insecure_turn: pipeline.ice.allow_insecure_turn,
+            recording: pipeline.recording,
+            local_preview: pipeline.local_preview,
+            sdp_dump_dir: pipeline.sdp_dump_dir,
+            dtls: DtlsConfig::default(),
+            max_restarts: 5,
+            metrics_addr: None,
+        }
+    }
+}
+
+impl Config {
+    /// Falls back to the defaults when the file doesn't exist, a file that
+    /// exists but doesn't parse is an error.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        if !path.exists() {
+            info!(path = %path.display(), "config not found, using defaults");
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read config {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("malformed config {}", path.display()))
+    }
+
+    /// How the pipeline actors are restarted, see `WebRTCBinActor::restart_strategy`.
+    pub fn restart_strategy(&self) -> RestartStrategy {
+        WebRTCBinActor::restart_strategy(self.max_restarts)
+    }
+
+    /// Fails when the DTLS certificate is incomplete or doesn't parse.
+    pub fn pipeline_config(&self) -> Result<PipelineConfig, anyhow::Error> {
+        Ok(PipelineConfig {
+            ice: IceConfig {
+                mode: self.ice_mode,
+                stun_servers: self.stun_servers.clone(),
+                turn_servers: self.turn_servers.clone(),
+                time_limited_turn: self.time_limited_turn.clone(),
+                allow_insecure_turn: self.allow_insecure_turn,
+            },
+            video_codec: self.video_codec,
+            codec_preference: self.codec_preference.clone(),
+            video_source: self.video_source.clone(),
+            restart_source_on_eos: self.restart_source_on_eos,
+            bitrate_kbps: self.bitrate_kbps,
+            max_bitrate_kbps: self.max_bitrate_kbps,
+            key_int_max: self.key_int_max,
+            x264: self.x264,
+            video: VideoConfig {
+                width: self.width,
+                height: self.height,
+                framerate: self.framerate,
+            },
+            transceiver_mode: self.transceiver_mode,
+            incoming_video_sink: self.incoming_video_sink.clone(),
+            composite_incoming: self.composite_incoming,
+            video_queue_leaky: self.video_queue_leaky,
+            video_queue_max_time_ms: self.video_queue_max_time_ms,
+            latency: self.latency,
+            audio: self.audio,
+            opus: self.opus,
+            bandwidth_estimation: self.bandwidth_estimation,
+            quality_thresholds: self.quality_thresholds,
+            enable_rtx: self.enable_rtx,
+            simulcast: self.simulcast,
+            rtcp_mux: self.rtcp_mux,
+            recording: self.recording.clone(),
+            local_preview: self.local_preview,
+            sdp_dump_dir: self.sdp_dump_dir.clone(),
+            dtls_certificate: self.dtls.certificate()?,
+            ..PipelineConfig::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bastion::supervisor::RestartPolicy;
+
+    use super::*;
+    use crate::codec::{EncoderPreset, EncoderTune};
+
+    #[test]
+    fn empty_config_is_the_default() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.rtcp_mux, RtcpMuxPolicy::Require);
+        assert_eq!(config.video_codec, Config::default().video_codec);
+    }
+
+    #[test]
+    fn rtcp_mux_policy() {
+        let config: Config = toml::from_str(r#"rtcp_mux = "require""#).unwrap();
+        assert_eq!(config.rtcp_mux, RtcpMuxPolicy::Require);
+        assert_eq!(config.pipeline_config().unwrap().rtcp_mux, RtcpMuxPolicy::Require);
+
+        // webrtcbin can't fall back to separate RTCP ports
+        assert!(toml::from_str::<Config>(r#"rtcp_mux = "negotiate""#).is_err());
+    }
+
+    #[test]
+    fn video_queue() {
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.video_queue_leaky);
+        assert_eq!(config.video_queue_max_time_ms, 200);
+
+        let config: Config =
+            toml::from_str("video_queue_leaky = false\nvideo_queue_max_time_ms = 500").unwrap();
+        let pipeline = config.pipeline_config().unwrap();
+        assert!(!pipeline.video_queue_leaky);
+        assert_eq!(pipeline.video_queue_max_time_ms, 500);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("bitrate = 600").is_err());
+    }
+
+    #[test]
+    fn x264_presets() {
+        for (name, preset) in [
+            ("ultra_fast", EncoderPreset::UltraFast),
+            ("fast", EncoderPreset::Fast),
+            ("medium", EncoderPreset::Medium),
+            ("slow", EncoderPreset::Slow),
+        ] {
+            let config: Config =
+                toml::from_str(&format!(r#"x264 = {{ preset = "{}" }}"#, name)).unwrap();
+            assert_eq!(config.x264.preset, preset);
+            assert_eq!(config.x264.tune, Some(EncoderTune::ZeroLatency));
+        }
+
+        let config: Config =
+            toml::from_str(r#"x264 = { preset = "medium", tune = "fast_decode" }"#).unwrap();
+        assert_eq!(config.x264.tune, Some(EncoderTune::FastDecode));
+        assert!(toml::from_str::<Config>(r#"x264 = { preset = "placebo" }"#).is_err());
+    }
+
+    #[test]
+    fn max_restarts_reaches_the_strategy() {
+        let config: Config = toml::from_str("max_restarts = 2").unwrap();
+        assert_eq!(config.restart_strategy().restart_policy(), RestartPolicy::Tries(2));
+        assert_eq!(Config::default().restart_strategy().restart_policy(), RestartPolicy::Tries(5));
+    }
+}
@@ -1,9 +1,263 @@
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, Mutex, Weak};
 
-struct Connection(Arc<ConnectionInner>);
+use anyhow::bail;
+use gst::prelude::ObjectExt;
+use serde::Serialize;
 
-struct ConnectionWeak(Weak<ConnectionInner>);
+/// webrtcbin's `connection-state`, as published to the `conn_state_{type}`
+/// distributor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerConnectionState {
+    New,
+    Connecting,
+    Connected,
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+impl From<gst_webrtc::WebRTCPeerConnectionState> for PeerConnectionState {
+    fn from(state: gst_webrtc::WebRTCPeerConnectionState) -> Self {
+        use gst_webrtc::WebRTCPeerConnectionState as State;
+
+        match state {
+            State::Connecting => Self::Connecting,
+            State::Connected => Self::Connected,
+            State::Disconnected => Self::Disconnected,
+            State::Failed => Self::Failed,
+            State::Closed => Self::Closed,
+            _ => Self::New,
+        }
+    }
+}
+
+/// Negotiation and transport lifecycle of a single peer connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    New,
+    /// A local offer is set, waiting for the remote answer.
+    Offering,
+    /// Both descriptions are set.
+    Answered,
+    Connected,
+    Failed,
+    Closed,
+}
+
+impl ConnectionState {
+    fn can_become(&self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+
+        match (self, next) {
+            (Closed, _) => false,
+            (_, Failed) | (_, Closed) => true,
+            // Answered straight from New when the remote makes the offer
+            (New, Offering) | (New, Answered) | (Offering, Answered) => true,
+            (Answered, Connected) => true,
+            // Renegotiation and ICE restarts start over with a new offer, or with
+            // the remote's one when it is the offerer
+            (Answered, Offering) | (Connected, Offering) | (Failed, Offering) => true,
+            (Connected, Answered) | (Failed, Answered) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Connection(Arc<ConnectionInner>);
+
+#[derive(Debug, Clone)]
+pub struct ConnectionWeak(Weak<ConnectionInner>);
+
+#[derive(Debug)]
+pub struct ConnectionInner {
+    webrtcbin: gst::Element,
+    state: Mutex<ConnectionState>,
+    /// Where a rollback of the current offer returns to.
+    before_offer: Mutex<ConnectionState>,
+    /// Remote candidates held back until the remote description is set,
+    /// `None` once they go straight to webrtcbin.
+    pending_candidates: Mutex<Option<Vec<(u32, String)>>>,
+}
+
+impl std::ops::Deref for Connection {
+    type Target = ConnectionInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl ConnectionWeak {
+    pub fn upgrade(&self) -> Option<Connection> {
+        self.0.upgrade().map(Connection)
+    }
+}
+
+impl Connection {
+    pub fn downgrade(&self) -> ConnectionWeak {
+        ConnectionWeak(Arc::downgrade(&self.0))
+    }
+}
+
+impl Connection {
+    pub fn new(webrtcbin: gst::Element) -> Self {
+        Self(Arc::new(ConnectionInner {
+            webrtcbin,
+            state: Mutex::new(ConnectionState::New),
+            before_offer: Mutex::new(ConnectionState::New),
+            pending_candidates: Mutex::new(Some(Vec::new())),
+        }))
+    }
+
+    pub fn webrtcbin(&self) -> &gst::Element {
+        &self.webrtcbin
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Moves to `next`, refusing steps the lifecycle doesn't allow.
+    ///
+    /// A new offer holds remote candidates back again until the answer is set.
+    pub fn transition(&self, next: ConnectionState) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        if *state == next {
+            return Ok(());
+        }
+        if !state.can_become(next) {
+            bail!("invalid connection state transition {:?} -> {:?}", *state, next);
+        }
+        if next == ConnectionState::Offering {
+            *self.before_offer.lock().unwrap() = *state;
+        }
+        *state = next;
+
+        if next == ConnectionState::Offering {
+            self.pending_candidates
+                .lock()
+                .unwrap()
+                .get_or_insert_with(Vec::new);
+        }
+
+        Ok(())
+    }
+
+    /// Abandons the local offer after glare, back to where it was made from.
+    pub fn rollback(&self) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        if *state != ConnectionState::Offering {
+            bail!("no local offer to roll back in state {:?}", *state);
+        }
+        *state = *self.before_offer.lock().unwrap();
+
+        Ok(())
+    }
+
+    /// Back to `New` for a pipeline that is started again.
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = ConnectionState::New;
+        *self.before_offer.lock().unwrap() = ConnectionState::New;
+        *self.pending_candidates.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Hands a remote candidate to webrtcbin, or holds it back while the
+    /// remote description isn't set yet.
+    pub fn add_ice_candidate(&self, mlineindex: u32, candidate: &str) -> Result<(), anyhow::Error> {
+        if let Some(pending) = self.pending_candidates.lock().unwrap().as_mut() {
+            pending.push((mlineindex, candidate.to_owned()));
+            return Ok(());
+        }
+
+        self.webrtcbin
+            .emit_by_name("add-ice-candidate", &[&mlineindex, &candidate])?;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn pending_candidates(&self) -> Vec<(u32, String)> {
+        self.pending_candidates.lock().unwrap().clone().unwrap_or_default()
+    }
+
+    /// Must follow `set-remote-description`, flushes the held back candidates.
+    ///
+    /// webrtcbin queues its operations, so candidates emitted right after the
+    /// description are applied after it.
+    pub fn remote_description_set(&self) -> Result<(), anyhow::Error> {
+        let pending = self.pending_candidates.lock().unwrap().take();
+        for (mlineindex, candidate) in pending.into_iter().flatten() {
+            self.webrtcbin
+                .emit_by_name("add-ice-candidate", &[&mlineindex, &candidate])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Connection,
+        ConnectionState::{self, *},
+    };
+
+    #[test]
+    fn remote_offers_renegotiate() {
+        assert!(New.can_become(Answered));
+        assert!(Connected.can_become(Answered));
+        assert!(Failed.can_become(Answered));
+    }
+
+    #[test]
+    fn local_offers_renegotiate() {
+        assert!(New.can_become(Offering));
+        assert!(Offering.can_become(Answered));
+        assert!(Answered.can_become(Offering));
+        assert!(Connected.can_become(Offering));
+        assert!(Failed.can_become(Offering));
+    }
+
+    #[test]
+    fn closed_is_final() {
+        let all: [ConnectionState; 6] = [New, Offering, Answered, Connected, Failed, Closed];
+        for next in all {
+            assert!(!Closed.can_become(next));
+        }
+        for state in all {
+            if state != Closed {
+                assert!(state.can_become(Failed));
+                assert!(state.can_become(Closed));
+            }
+        }
+    }
+
+    #[test]
+    fn connected_needs_both_descriptions() {
+        assert!(!New.can_become(Connected));
+        assert!(!Offering.can_become(Connected));
+        assert!(Answered.can_become(Connected));
+    }
+
+    #[test]
+    fn rollback_returns_to_before_the_offer() {
+        gst::init().unwrap();
+        let conn = Connection::new(gst::ElementFactory::make("webrtcbin", None).unwrap());
+        assert!(conn.rollback().is_err());
+
+        conn.transition(Offering).unwrap();
+        conn.rollback().unwrap();
+        assert_eq!(conn.state(), New);
 
-struct ConnectionInner {
-    peer_conn: Option<String>,
+        conn.transition(Answered).unwrap();
+        conn.transition(Connected).unwrap();
+        conn.transition(Offering).unwrap();
+        conn.rollback().unwrap();
+        assert_eq!(conn.state(), Connected);
+        // Only the offer itself can be rolled back
+        assert!(conn.rollback().is_err());
+        assert_eq!(conn.state(), Connected);
+    }
 }
@@ -18,6 +18,9 @@ impl ConsoleListener {
                         //         .supervisor(|s| s)
                         //         .expect("couldn't create new supervisor"),
                         //     SDP,
+                        //     "127.0.0.1:5004".parse().unwrap(),
+                        //     0,
+                        //     VideoCodec::Vp8,
                         // );
 
                         // WebRTCBinActor::run(
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::stats::PeerStats;
+
+/// Request sent by the peer over the "control" data channel.
+///
+/// ```json
+/// {"request_id": 7, "method": "set_bitrate", "params": {"kbps": 800}}
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum ControlRequest {
+    ListCameras,
+    GetStats,
+    SetBitrate { kbps: u32 },
+    ForceKeyframe,
+    IceRestart,
+}
+
+/// Answer to a `ControlRequest`, carrying the same `request_id`.
+///
+/// ```json
+/// {"request_id": 7, "type": "error", "code": "timeout", "message": "..."}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Ok,
+    Cameras { devices: Vec<String> },
+    Stats(PeerStats),
+    Error { code: ControlErrorCode, message: String },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlErrorCode {
+    /// The method is unknown or its params don't match.
+    InvalidRequest,
+    /// The request was understood but couldn't be carried out.
+    Failed,
+    /// No answer within `CONTROL_REQUEST_TIMEOUT`.
+    Timeout,
+}
+
+impl ControlResponse {
+    pub fn error(code: ControlErrorCode, err: impl ToString) -> Self {
+        Self::Error {
+            code,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Correlates a request or response with its counterpart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub request_id: u64,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+/// Splits incoming channel messages into control requests and everything else.
+///
+/// Messages without a `request_id` aren't meant for the dispatcher and come
+/// back as `None`. A request that doesn't parse is answered right away.
+pub fn parse_request(
+    msg: &str,
+) -> Option<Result<Envelope<ControlRequest>, Envelope<ControlResponse>>> {
+    let value = serde_json::from_str::<Value>(msg).ok()?;
+    let request_id = value.get("request_id")?.as_u64()?;
+
+    Some(serde_json::from_value(value).map_err(|err| Envelope {
+        request_id,
+        body: ControlResponse::error(ControlErrorCode::InvalidRequest, err),
+    }))
+}
+
+/// V4L2 capture devices, sorted by path.
+pub fn list_cameras() -> Vec<String> {
+    let mut devices = std::fs::read_dir("/dev")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .map_or(false, |name| name.starts_with("video"))
+                })
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    devices.sort();
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn requests_parse() {
+        let request = parse_request(
+            r#"{"request_id": 7, "method": "set_bitrate", "params": {"kbps": 800}}"#,
+        );
+        assert_eq!(
+            request,
+            Some(Ok(Envelope {
+                request_id: 7,
+                body: ControlRequest::SetBitrate { kbps: 800 },
+            }))
+        );
+
+        let request = parse_request(r#"{"request_id": 8, "method": "list_cameras"}"#);
+        assert_eq!(
+            request,
+            Some(Ok(Envelope {
+                request_id: 8,
+                body: ControlRequest::ListCameras,
+            }))
+        );
+    }
+
+    #[test]
+    fn other_messages_are_not_requests() {
+        assert_eq!(parse_request("hello"), None);
+        assert_eq!(parse_request(r#"{"method": "get_stats"}"#), None);
+        assert_eq!(parse_request(r#"{"request_id": "7", "method": "get_stats"}"#), None);
+    }
+
+    #[test]
+    fn malformed_requests_are_answered() {
+        let request = parse_request(r#"{"request_id": 3, "method": "reboot"}"#);
+        match request {
+            Some(Err(Envelope {
+                request_id: 3,
+                body: ControlResponse::Error { code, .. },
+            })) => assert_eq!(code, ControlErrorCode::InvalidRequest),
+            other => panic!("expected an invalid request error, got {:?}", other),
+        }
+
+        let request = parse_request(
+            r#"{"request_id": 4, "method": "set_bitrate", "params": {"kbps": "fast"}}"#,
+        );
+        assert!(matches!(request, Some(Err(Envelope { request_id: 4, .. }))));
+    }
+
+    #[test]
+    fn responses_serialize() {
+        let response = Envelope {
+            request_id: 7,
+            body: ControlResponse::Ok,
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({"request_id": 7, "type": "ok"})
+        );
+
+        let response = Envelope {
+            request_id: 7,
+            body: ControlResponse::error(ControlErrorCode::Timeout, "no answer in time"),
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "request_id": 7,
+                "type": "error",
+                "code": "timeout",
+                "message": "no answer in time",
+            })
+        );
+
+        let response = Envelope {
+            request_id: 8,
+            body: ControlResponse::Cameras {
+                devices: vec!["/dev/video0".to_owned()],
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({"request_id": 8, "type": "cameras", "devices": ["/dev/video0"]})
+        );
+
+        let response = Envelope {
+            request_id: 9,
+            body: ControlResponse::Stats(PeerStats {
+                bytes_sent: 1200,
+                ..Default::default()
+            }),
+        };
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["type"], "stats");
+        assert_eq!(value["bytes_sent"], 1200);
+    }
+}
@@ -1,12 +1,15 @@
+use std::net::SocketAddr;
+
 use bastion::supervisor::{ActorRestartStrategy, RestartPolicy, RestartStrategy, SupervisorRef};
 use gst::glib;
 
-use crate::pipeline::Pipeline;
+use crate::{codec::VideoCodec, pipeline::Pipeline};
 
 pub struct GstreamerActor;
 
 impl GstreamerActor {
-    pub fn run(parent: SupervisorRef) {
+    /// Streams the test video as `video_codec` RTP to `rtp_addr`.
+    pub fn run(parent: SupervisorRef, rtp_addr: SocketAddr, video_codec: VideoCodec) {
         parent
             .supervisor(|s| {
                 s.with_restart_strategy(
@@ -15,9 +18,9 @@ impl GstreamerActor {
                         .with_actor_restart_strategy(ActorRestartStrategy::Immediate),
                 )
                 .children(|c| {
-                    c.with_exec(|_| async {
+                    c.with_exec(move |_| async move {
                         let main_context = glib::MainContext::default();
-                        main_context.block_on(main_fn());
+                        main_context.block_on(main_fn(rtp_addr, video_codec));
                         loop {}
                     })
                 })
@@ -26,12 +29,12 @@ impl GstreamerActor {
     }
 }
 
-async fn main_fn() {
-    println!("Gstreamer started");
+async fn main_fn(rtp_addr: SocketAddr, video_codec: VideoCodec) {
+    tracing::info!("Gstreamer started");
 
     gst::init().expect("couldn't initialize gstreamer");
 
-    let pipeline = Pipeline::init().expect("couldn't initialize pipeline");
+    let pipeline = Pipeline::init(rtp_addr, video_codec).expect("couldn't initialize pipeline");
 
     pipeline.run().expect("couldn't run pipeline on");
 
@@ -3,29 +3,113 @@
 extern crate lazy_static;
 
 mod client;
+mod codec;
+mod config;
 mod console_listener;
+mod control;
+mod dtls;
 mod gstreamer_actor;
+mod ice;
+mod metrics;
 mod pipeline;
+mod signaling;
+mod stats;
+mod turn;
+mod utils;
 mod webrtc_actor;
 mod webrtcbin_actor;
 mod conn;
 
 use anyhow::Result;
 use bastion::prelude::*;
-use webrtcbin_actor::{WebRTCBinActor, WebRTCBinActorType};
+use config::Config;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+use webrtcbin_actor::{WebRTCBinActor, WebRTCBinActorType, WebRTCPipeline};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let config_path = std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_owned());
+    let config = Config::load(config_path.as_ref())?;
+    let metrics_addr = config.metrics_addr;
+    let restart_strategy = config.restart_strategy();
+    let config = config.pipeline_config()?;
+
+    if std::env::args().any(|arg| arg == "--print-pipeline") {
+        for type_ in [WebRTCBinActorType::Server, WebRTCBinActorType::Client] {
+            println!("{}: {}", type_.as_ref(), WebRTCPipeline::launch_string(&type_, &config));
+        }
+        return Ok(());
+    }
+
+    if let Some(addr) = metrics_addr {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr).await {
+                warn!(?err, "metrics endpoint stopped");
+            }
+        });
+    }
+
     Bastion::init();
     Bastion::start();
 
     let server_parent = Bastion::supervisor(|s| s).unwrap();
-    WebRTCBinActor::run(server_parent, WebRTCBinActorType::Server);
+    WebRTCBinActor::run(
+        server_parent,
+        WebRTCBinActorType::Server,
+        config.clone(),
+        restart_strategy.clone(),
+    );
 
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
     let client_parent = Bastion::supervisor(|s| s).unwrap();
-    WebRTCBinActor::run(client_parent, WebRTCBinActorType::Client);
+    WebRTCBinActor::run(
+        client_parent,
+        WebRTCBinActorType::Client,
+        config,
+        restart_strategy,
+    );
+
+    shutdown_signal().await;
+    info!("shutting down");
 
+    // Each actor answers once its pipeline is stopped, don't hang on one that doesn't
+    for type_ in [WebRTCBinActorType::Server, WebRTCBinActorType::Client] {
+        let reply = Distributor::named(type_.as_ref()).request::<&'static str>("shutdown");
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, reply).await {
+            Ok(Ok(Ok(_))) => info!(actor = type_.as_ref(), "stopped"),
+            Ok(_) => warn!(actor = type_.as_ref(), "didn't acknowledge shutdown"),
+            Err(_) => warn!(actor = type_.as_ref(), "timed out waiting for shutdown"),
+        }
+    }
+
+    Bastion::stop();
     Bastion::block_until_stopped();
+
+    Ok(())
+}
+
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("couldn't install SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = sigterm.recv() => (),
+    }
 }
@@ -0,0 +1,242 @@
+use std::{
+    collections::BTreeMap, convert::Infallible, fmt::Write, net::SocketAddr, sync::Mutex,
+    time::Duration,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use lazy_static::lazy_static;
+use tracing::info;
+
+const TIME_TO_CONNECTED_BUCKETS: [f64; 7] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+lazy_static! {
+    /// Time from `WebRTCPipeline::run` until webrtcbin reports `Connected`, by
+    /// the `path` label of the selected candidate pair.
+    static ref TIME_TO_CONNECTED: Mutex<BTreeMap<&'static str, Histogram>> =
+        Mutex::new(BTreeMap::new());
+
+    /// Latest gauges per actor, pushed by the pipelines so scrapes never wait on them.
+    static ref PIPELINES: Mutex<BTreeMap<String, PipelineGauges>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PipelineGauges {
+    /// `gst::State` as its numeric value, 4 is Playing.
+    state: i32,
+    /// `WebRTCPipeline::is_ready`.
+    ready: bool,
+    peers_active: u64,
+}
+
+pub fn set_pipeline_state(actor: &str, state: gst::State) {
+    let mut pipelines = PIPELINES.lock().unwrap();
+    pipelines.entry(actor.to_owned()).or_default().state = state as i32;
+}
+
+pub fn set_pipeline_ready(actor: &str, ready: bool) {
+    let mut pipelines = PIPELINES.lock().unwrap();
+    pipelines.entry(actor.to_owned()).or_default().ready = ready;
+}
+
+/// Healthy once at least one pipeline exists and every one of them is ready.
+pub fn is_healthy() -> bool {
+    let pipelines = PIPELINES.lock().unwrap();
+    !pipelines.is_empty() && pipelines.values().all(|gauges| gauges.ready)
+}
+
+pub fn set_peers_active(actor: &str, peers: u64) {
+    let mut pipelines = PIPELINES.lock().unwrap();
+    pipelines.entry(actor.to_owned()).or_default().peers_active = peers;
+}
+
+/// `path` is `CandidatePath::label`, or "unknown" when the stats had no pair.
+pub fn observe_time_to_connected(path: &'static str, elapsed: Duration) {
+    let mut histograms = TIME_TO_CONNECTED.lock().unwrap();
+    histograms
+        .entry(path)
+        .or_insert_with(|| Histogram::new(&TIME_TO_CONNECTED_BUCKETS))
+        .observe(elapsed);
+}
+
+/// Everything above in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let pipelines = PIPELINES.lock().unwrap().clone();
+    let _ = writeln!(out, "# TYPE webrtc_peers_active gauge");
+    for (actor, gauges) in &pipelines {
+        let _ = writeln!(
+            out,
+            "webrtc_peers_active{{actor=\"{}\"}} {}",
+            actor, gauges.peers_active
+        );
+    }
+    let _ = writeln!(out, "# TYPE webrtc_pipeline_state gauge");
+    for (actor, gauges) in &pipelines {
+        let _ = writeln!(out, "webrtc_pipeline_state{{actor=\"{}\"}} {}", actor, gauges.state);
+    }
+    let _ = writeln!(out, "# TYPE webrtc_pipeline_ready gauge");
+    for (actor, gauges) in &pipelines {
+        let _ = writeln!(
+            out,
+            "webrtc_pipeline_ready{{actor=\"{}\"}} {}",
+            actor, gauges.ready as u8
+        );
+    }
+
+    let _ = writeln!(out, "# TYPE webrtc_time_to_connected_seconds histogram");
+    for (path, histogram) in TIME_TO_CONNECTED.lock().unwrap().iter() {
+        let (buckets, count, sum) = histogram.snapshot();
+        for (bound, bucket_count) in buckets {
+            let _ = writeln!(
+                out,
+                "webrtc_time_to_connected_seconds_bucket{{path=\"{}\",le=\"{}\"}} {}",
+                path, bound, bucket_count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "webrtc_time_to_connected_seconds_bucket{{path=\"{}\",le=\"+Inf\"}} {}",
+            path, count
+        );
+        let _ = writeln!(
+            out,
+            "webrtc_time_to_connected_seconds_sum{{path=\"{}\"}} {}",
+            path, sum
+        );
+        let _ = writeln!(
+            out,
+            "webrtc_time_to_connected_seconds_count{{path=\"{}\"}} {}",
+            path, count
+        );
+    }
+
+    out
+}
+
+/// Serves `GET /metrics`, and `GET /health` answering 503 until every
+/// pipeline is ready, on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr) -> Result<(), anyhow::Error> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            match (req.method(), req.uri().path()) {
+                (&Method::GET, "/metrics") => Response::builder()
+                    .header("content-type", "text/plain; version=0.0.4")
+                    .body(Body::from(render())),
+                (&Method::GET, "/health") if is_healthy() => {
+                    Response::builder().body(Body::from("ok"))
+                }
+                (&Method::GET, "/health") => Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from("not ready")),
+                _ => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty()),
+            }
+        }))
+    });
+
+    info!(%addr, "serving metrics");
+    Server::try_bind(&addr)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+/// Cumulative histogram with Prometheus-style `le` buckets, in seconds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    inner: Mutex<HistogramInner>,
+}
+
+#[derive(Debug, Default)]
+struct HistogramInner {
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            inner: Mutex::new(HistogramInner {
+                counts: vec![0; bounds.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let mut inner = self.inner.lock().unwrap();
+        for (bound, count) in self.bounds.iter().zip(inner.counts.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        inner.count += 1;
+        inner.sum += secs;
+    }
+
+    /// `(upper bound, cumulative count)` per bucket, followed by the total count and sum.
+    pub fn snapshot(&self) -> (Vec<(f64, u64)>, u64, f64) {
+        let inner = self.inner.lock().unwrap();
+        let buckets = self
+            .bounds
+            .iter()
+            .copied()
+            .zip(inner.counts.iter().copied())
+            .collect();
+        (buckets, inner.count, inner.sum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[0.5, 1.0, 2.5]);
+        histogram.observe(Duration::from_millis(200));
+        histogram.observe(Duration::from_millis(1000));
+        histogram.observe(Duration::from_millis(1500));
+        histogram.observe(Duration::from_secs(4));
+
+        let (buckets, count, sum) = histogram.snapshot();
+        assert_eq!(buckets, vec![(0.5, 1), (1.0, 2), (2.5, 3)]);
+        assert_eq!(count, 4);
+        assert!((sum - 6.7).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_histogram() {
+        let histogram = Histogram::new(&TIME_TO_CONNECTED_BUCKETS);
+        let (buckets, count, sum) = histogram.snapshot();
+        assert_eq!(buckets.len(), TIME_TO_CONNECTED_BUCKETS.len());
+        assert!(buckets.iter().all(|(_, count)| *count == 0));
+        assert_eq!(count, 0);
+        assert_eq!(sum, 0.0);
+    }
+
+    #[test]
+    fn time_to_connected_is_labelled_by_path() {
+        observe_time_to_connected("relayed", Duration::from_millis(300));
+
+        let out = render();
+        let lines: Vec<&str> = out.lines().collect();
+        for expected in &[
+            "# TYPE webrtc_time_to_connected_seconds histogram",
+            r#"webrtc_time_to_connected_seconds_bucket{path="relayed",le="0.25"} 0"#,
+            r#"webrtc_time_to_connected_seconds_bucket{path="relayed",le="0.5"} 1"#,
+            r#"webrtc_time_to_connected_seconds_bucket{path="relayed",le="+Inf"} 1"#,
+            r#"webrtc_time_to_connected_seconds_count{path="relayed"} 1"#,
+        ] {
+            assert!(lines.contains(expected), "missing {:?} in\n{}", expected, out);
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use std::sync::{Arc, Weak};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Weak},
+};
 
 use anyhow::bail;
 use gst::{
@@ -6,6 +9,8 @@ use gst::{
     prelude::{Cast, ElementExt, ElementExtManual, GstObjectExt},
 };
 
+use crate::codec::{VideoCodec, X264Config};
+
 #[macro_export]
 macro_rules! upgrade_weak {
     ($x:ident, $r:expr) => {{
@@ -57,10 +62,19 @@ impl Pipeline {
 }
 
 impl Pipeline {
-    pub fn init() -> Result<Self, anyhow::Error> {
-        let pipeline = gst::parse_launch(
-            "videotestsrc pattern=ball is-live=true ! video/x-raw,width=640,height=480,format=I420 ! vp8enc error-resilient=partitions keyframe-max-dist=10 auto-alt-ref=true cpu-used=5 deadline=1 ! rtpvp8pay ! udpsink host=127.0.0.1 port=5004",
-        )
+    pub fn init(rtp_addr: SocketAddr, video_codec: VideoCodec) -> Result<Self, anyhow::Error> {
+        // udpsink would pick a random port just like the relay's socket
+        if rtp_addr.port() == 0 {
+            bail!("RTP address {} needs a fixed port", rtp_addr);
+        }
+
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc pattern=ball is-live=true ! video/x-raw,width=640,height=480,format=I420 ! {} ! {} ! udpsink host={} port={}",
+            video_codec.encoder("encoder", 256, 10, &X264Config::default()),
+            video_codec.payloader("payloader", 96, &[]),
+            rtp_addr.ip(),
+            rtp_addr.port()
+        ))
         .expect("couldn't parse pipeline from string");
 
         let pipeline = pipeline
@@ -107,7 +121,7 @@ fn handle_pipeline_msg(msg: &gst::Message) -> Result<(), anyhow::Error> {
             err.debug().unwrap_or_else(|| String::from("None")),
         ),
         MessageView::Warning(warning) => {
-            println!("Warning: \"{}\"", warning.debug().unwrap());
+            tracing::warn!(debug = ?warning.debug(), "{}", warning.error());
         }
         _ => (),
     }
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+
+type SDPType = gst_webrtc::WebRTCSDPType;
+
+/// The message was malformed, or webrtcbin refused the description or candidate.
+pub const ERROR_REJECTED: u16 = 400;
+
+/// Message exchanged with the remote peer over the signaling channel.
+///
+/// ```json
+/// {"sdp": {"type": "offer", "sdp": "v=0..."}}
+/// {"ice": {"candidate": "candidate:...", "sdpMLineIndex": 0, "sdpMid": "video0"}}
+/// {"error": {"code": 400, "reason": "..."}}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonMsg {
+    Ice(IceCandidate),
+    Sdp {
+        #[serde(rename = "type")]
+        type_: String,
+        sdp: String,
+    },
+    /// The other side couldn't act on a message, e.g. its offer was rejected.
+    Error { code: u16, reason: String },
+}
+
+/// Candidate in the browser's `RTCIceCandidateInit` form.
+///
+/// Always serialized with `sdpMid`, older senders that only know the mline
+/// index or spell the fields in snake case are still accepted.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IceCandidate {
+    /// Empty or absent for end-of-candidates.
+    #[serde(default)]
+    pub candidate: Option<String>,
+    #[serde(rename = "sdpMLineIndex", alias = "sdp_mline_index", default)]
+    pub sdp_mline_index: Option<u32>,
+    #[serde(rename = "sdpMid", alias = "sdp_mid", default)]
+    pub sdp_mid: Option<String>,
+}
+
+/// Parses the `type` of an SDP message.
+pub fn sdp_type(type_: &str) -> Option<SDPType> {
+    match type_ {
+        "offer" => Some(SDPType::Offer),
+        "answer" => Some(SDPType::Answer),
+        "pranswer" => Some(SDPType::Pranswer),
+        "rollback" => Some(SDPType::Rollback),
+        _ => None,
+    }
+}
+
+/// Inverse of `sdp_type`.
+pub fn sdp_type_name(type_: SDPType) -> Option<&'static str> {
+    match type_ {
+        SDPType::Offer => Some("offer"),
+        SDPType::Answer => Some("answer"),
+        SDPType::Pranswer => Some("pranswer"),
+        SDPType::Rollback => Some("rollback"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CANDIDATE: &str = "candidate:1 1 UDP 2013266431 10.0.0.1 5000 typ host";
+
+    #[test]
+    fn ice_from_web_socket_without_mid() {
+        let json = serde_json::json!({"ice": {"candidate": CANDIDATE, "sdpMLineIndex": 0}});
+        let msg: JsonMsg = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            msg,
+            JsonMsg::Ice(IceCandidate {
+                candidate: Some(CANDIDATE.into()),
+                sdp_mline_index: Some(0),
+                sdp_mid: None,
+            })
+        );
+    }
+
+    #[test]
+    fn ice_from_nats_in_snake_case() {
+        let json = serde_json::json!({
+            "ice": {"candidate": CANDIDATE, "sdp_mline_index": 1, "sdp_mid": "video0"}
+        });
+        let msg: JsonMsg = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            msg,
+            JsonMsg::Ice(IceCandidate {
+                candidate: Some(CANDIDATE.into()),
+                sdp_mline_index: Some(1),
+                sdp_mid: Some("video0".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn ice_end_of_candidates() {
+        let msg: JsonMsg = serde_json::from_str(r#"{"ice": {"candidate": null}}"#).unwrap();
+        assert_eq!(msg, JsonMsg::Ice(IceCandidate::default()));
+
+        let msg: JsonMsg = serde_json::from_str(r#"{"ice": {"sdpMid": "0"}}"#).unwrap();
+        assert_eq!(
+            msg,
+            JsonMsg::Ice(IceCandidate {
+                sdp_mid: Some("0".into()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn ice_serializes_in_browser_form() {
+        let msg = JsonMsg::Ice(IceCandidate {
+            candidate: Some("candidate:1".into()),
+            sdp_mline_index: Some(0),
+            sdp_mid: Some("video0".into()),
+        });
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "ice": {"candidate": "candidate:1", "sdpMLineIndex": 0, "sdpMid": "video0"}
+            })
+        );
+        assert_eq!(serde_json::from_value::<JsonMsg>(json).unwrap(), msg);
+    }
+
+    #[test]
+    fn sdp_round_trip() {
+        let msg: JsonMsg =
+            serde_json::from_str(r#"{"sdp": {"type": "offer", "sdp": "v=0\r\n"}}"#).unwrap();
+        assert_eq!(
+            msg,
+            JsonMsg::Sdp {
+                type_: "offer".into(),
+                sdp: "v=0\r\n".into(),
+            }
+        );
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(serde_json::from_str::<JsonMsg>(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn error_round_trip() {
+        let msg: JsonMsg =
+            serde_json::from_str(r#"{"error": {"code": 400, "reason": "bad offer"}}"#).unwrap();
+        assert_eq!(
+            msg,
+            JsonMsg::Error {
+                code: ERROR_REJECTED,
+                reason: "bad offer".into(),
+            }
+        );
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(serde_json::from_str::<JsonMsg>(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn sdp_type_names() {
+        for name in ["offer", "answer", "pranswer", "rollback"] {
+            let type_ = sdp_type(name).unwrap();
+            assert_eq!(sdp_type_name(type_), Some(name));
+        }
+        assert_eq!(sdp_type("Offer"), None);
+        assert_eq!(sdp_type(""), None);
+    }
+}
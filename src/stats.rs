@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use gst_webrtc::WebRTCStatsType;
+use serde::{Deserialize, Serialize};
+
+/// Subset of webrtcbin's `get-stats` report for the outgoing media.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PeerStats {
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    pub packets_lost: i64,
+    pub rtt_ms: f64,
+    pub jitter: f64,
+}
+
+/// Reception report block the remote sent about one of the webrtc-rs
+/// relay's streams, posted to the `rtcp_relay` distributor.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize)]
+pub struct ReceptionStats {
+    pub ssrc: u32,
+    /// Loss since the previous report.
+    pub fraction_lost_pct: f64,
+    pub total_lost: u32,
+    pub jitter_ms: f64,
+}
+
+/// Transport-wide congestion control figures of the bundled RTP session,
+/// posted every `BWE_INTERVAL` to the `bwe_{type}` distributor.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize)]
+pub struct BandwidthEstimate {
+    /// What the remote acknowledged receiving, i.e. the usable bitrate.
+    pub bitrate_recv: u32,
+    pub bitrate_sent: u32,
+    pub packet_loss_pct: f64,
+}
+
+impl BandwidthEstimate {
+    /// Reads rtpsession's `twcc-stats`, fields missing before the first feedback are 0.
+    pub fn from_structure(stats: &gst::StructureRef) -> Self {
+        Self {
+            bitrate_recv: stats.get::<u32>("bitrate-recv").unwrap_or(0),
+            bitrate_sent: stats.get::<u32>("bitrate-sent").unwrap_or(0),
+            packet_loss_pct: stats.get::<f64>("packet-loss-pct").unwrap_or(0.0),
+        }
+    }
+}
+
+impl PeerStats {
+    /// Sums the outbound-rtp and remote-inbound-rtp entries of a `get-stats` reply.
+    pub fn from_structure(reply: &gst::StructureRef) -> Self {
+        let mut stats = Self::default();
+
+        for (_, value) in reply.iter() {
+            let entry = match value.get::<gst::Structure>() {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            match entry.get::<WebRTCStatsType>("type") {
+                Ok(WebRTCStatsType::OutboundRtp) => {
+                    stats.bytes_sent += entry.get::<u64>("bytes-sent").unwrap_or(0);
+                    stats.packets_sent += entry.get::<u64>("packets-sent").unwrap_or(0);
+                }
+                Ok(WebRTCStatsType::RemoteInboundRtp) => {
+                    stats.packets_lost += entry.get::<i32>("packets-lost").unwrap_or(0) as i64;
+                    stats.rtt_ms = stats
+                        .rtt_ms
+                        .max(entry.get::<f64>("round-trip-time").unwrap_or(0.0) * 1000.0);
+                    stats.jitter = stats.jitter.max(entry.get::<f64>("jitter").unwrap_or(0.0));
+                }
+                _ => (),
+            }
+        }
+
+        stats
+    }
+
+    /// Share of the sent packets the remote reported lost.
+    pub fn packet_loss_pct(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+        self.packets_lost.max(0) as f64 * 100.0 / self.packets_sent as f64
+    }
+
+    /// Bucket of the worst of packet loss, round trip time and jitter.
+    pub fn quality(&self, thresholds: &QualityThresholds) -> ConnectionQuality {
+        let bucket = |value: f64, limits: &[f64; 3]| {
+            limits.iter().position(|limit| value <= *limit).unwrap_or(3)
+        };
+
+        let worst = bucket(self.packet_loss_pct(), &thresholds.packet_loss_pct)
+            .max(bucket(self.rtt_ms, &thresholds.rtt_ms))
+            .max(bucket(self.jitter * 1000.0, &thresholds.jitter_ms));
+        match worst {
+            0 => ConnectionQuality::Excellent,
+            1 => ConnectionQuality::Good,
+            2 => ConnectionQuality::Poor,
+            _ => ConnectionQuality::Critical,
+        }
+    }
+}
+
+/// How the selected candidate pair reaches the peer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CandidatePath {
+    Direct,
+    /// Through a TURN server, one side of the pair is a relay candidate.
+    Relayed,
+}
+
+impl CandidatePath {
+    /// Reads the candidate-pair entry of a `get-stats` reply and the types of
+    /// its candidates, `None` when the reply has no pair yet.
+    pub fn from_structure(reply: &gst::StructureRef) -> Option<Self> {
+        let mut candidate_types = HashMap::new();
+        let mut pair = None;
+        for (_, value) in reply.iter() {
+            let entry = match value.get::<gst::Structure>() {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            match entry.get::<WebRTCStatsType>("type") {
+                Ok(WebRTCStatsType::LocalCandidate) | Ok(WebRTCStatsType::RemoteCandidate) => {
+                    if let (Ok(id), Ok(type_)) =
+                        (entry.get::<String>("id"), entry.get::<String>("candidate-type"))
+                    {
+                        candidate_types.insert(id, type_);
+                    }
+                }
+                Ok(WebRTCStatsType::CandidatePair) if pair.is_none() => {
+                    pair = Some([
+                        entry.get::<String>("local-candidate-id").unwrap_or_default(),
+                        entry.get::<String>("remote-candidate-id").unwrap_or_default(),
+                    ]);
+                }
+                _ => (),
+            }
+        }
+
+        let relayed = pair?
+            .iter()
+            .any(|id| candidate_types.get(id).map(String::as_str) == Some("relay"));
+        Some(if relayed { Self::Relayed } else { Self::Direct })
+    }
+
+    /// Value of the `path` metrics label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Direct => "direct",
+            Self::Relayed => "relayed",
+        }
+    }
+}
+
+/// Coarse summary of `PeerStats` for UIs that don't want raw numbers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionQuality {
+    Excellent,
+    Good,
+    Poor,
+    Critical,
+}
+
+/// Inclusive upper bounds for `Excellent`, `Good` and `Poor`, anything above
+/// the last one is `Critical`.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QualityThresholds {
+    pub packet_loss_pct: [f64; 3],
+    pub rtt_ms: [f64; 3],
+    pub jitter_ms: [f64; 3],
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            packet_loss_pct: [1.0, 3.0, 10.0],
+            rtt_ms: [100.0, 250.0, 500.0],
+            jitter_ms: [20.0, 50.0, 100.0],
+        }
+    }
+}
+
+impl QualityThresholds {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for (name, limits) in [
+            ("packet_loss_pct", &self.packet_loss_pct),
+            ("rtt_ms", &self.rtt_ms),
+            ("jitter_ms", &self.jitter_ms),
+        ] {
+            if limits[0] < 0.0 || limits[0] > limits[1] || limits[1] > limits[2] {
+                bail!("{} thresholds {:?} must be non-negative and ascending", name, limits);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(type_: WebRTCStatsType, id: &str, fields: &[(&str, &str)]) -> gst::Structure {
+        let mut entry = gst::Structure::builder("stats")
+            .field("type", &type_)
+            .field("id", &id)
+            .build();
+        for (name, value) in fields {
+            entry.set(name, value);
+        }
+        entry
+    }
+
+    fn reply(entries: Vec<gst::Structure>) -> gst::Structure {
+        let mut reply = gst::Structure::new_empty("application/x-webrtc-stats");
+        for entry in entries {
+            let name = entry.get::<String>("id").unwrap();
+            reply.set(&name, &entry);
+        }
+        reply
+    }
+
+    fn pair_with(local_type: &str) -> gst::Structure {
+        reply(vec![
+            entry(WebRTCStatsType::LocalCandidate, "local", &[("candidate-type", local_type)]),
+            entry(WebRTCStatsType::RemoteCandidate, "remote", &[("candidate-type", "srflx")]),
+            entry(
+                WebRTCStatsType::CandidatePair,
+                "pair",
+                &[("local-candidate-id", "local"), ("remote-candidate-id", "remote")],
+            ),
+        ])
+    }
+
+    #[test]
+    fn candidate_path_from_the_selected_pair() {
+        gst::init().unwrap();
+        assert_eq!(CandidatePath::from_structure(&pair_with("host")), Some(CandidatePath::Direct));
+        assert_eq!(
+            CandidatePath::from_structure(&pair_with("relay")),
+            Some(CandidatePath::Relayed)
+        );
+        assert_eq!(CandidatePath::from_structure(&reply(Vec::new())), None);
+    }
+
+    fn stats(packets_lost: i64, rtt_ms: f64, jitter_ms: f64) -> PeerStats {
+        PeerStats {
+            packets_sent: 1000,
+            packets_lost,
+            rtt_ms,
+            jitter: jitter_ms / 1000.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn quality_buckets() {
+        let thresholds = QualityThresholds::default();
+        assert_eq!(stats(0, 20.0, 5.0).quality(&thresholds), ConnectionQuality::Excellent);
+        // Limits are inclusive
+        assert_eq!(stats(10, 100.0, 20.0).quality(&thresholds), ConnectionQuality::Excellent);
+        assert_eq!(stats(11, 100.0, 20.0).quality(&thresholds), ConnectionQuality::Good);
+        assert_eq!(stats(0, 300.0, 5.0).quality(&thresholds), ConnectionQuality::Poor);
+        assert_eq!(stats(0, 20.0, 101.0).quality(&thresholds), ConnectionQuality::Critical);
+    }
+
+    #[test]
+    fn quality_is_the_worst_metric() {
+        let thresholds = QualityThresholds::default();
+        assert_eq!(stats(20, 300.0, 5.0).quality(&thresholds), ConnectionQuality::Poor);
+        assert_eq!(stats(200, 20.0, 5.0).quality(&thresholds), ConnectionQuality::Critical);
+    }
+
+    #[test]
+    fn packet_loss_without_packets() {
+        let stats = PeerStats {
+            packets_lost: 3,
+            ..Default::default()
+        };
+        assert_eq!(stats.packet_loss_pct(), 0.0);
+
+        // RTCP reports can count duplicates as negative loss
+        let stats = PeerStats {
+            packets_sent: 100,
+            packets_lost: -2,
+            ..Default::default()
+        };
+        assert_eq!(stats.packet_loss_pct(), 0.0);
+    }
+
+    #[test]
+    fn thresholds_must_ascend() {
+        assert!(QualityThresholds::default().validate().is_ok());
+        let thresholds = QualityThresholds {
+            rtt_ms: [100.0, 50.0, 500.0],
+            ..Default::default()
+        };
+        assert!(thresholds.validate().is_err());
+        let thresholds = QualityThresholds {
+            jitter_ms: [-1.0, 50.0, 100.0],
+            ..Default::default()
+        };
+        assert!(thresholds.validate().is_err());
+    }
+
+    #[test]
+    fn quality_serializes_in_snake_case() {
+        assert_eq!(
+            serde_json::to_value(ConnectionQuality::Excellent).unwrap(),
+            serde_json::json!("excellent")
+        );
+        assert!(ConnectionQuality::Good < ConnectionQuality::Critical);
+    }
+}
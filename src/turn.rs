@@ -0,0 +1,79 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use serde::Deserialize;
+
+use crate::ice::TurnServer;
+
+/// TURN server using time-limited credentials from a shared secret, coturn's
+/// `use-auth-secret` / TURN REST API scheme.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimeLimitedTurn {
+    /// `turn:` or `turns:` url, as for `TurnServer`.
+    pub url: String,
+    pub username: String,
+    /// coturn's `static-auth-secret`.
+    pub secret: String,
+    /// How long each credential is valid, a new one is issued after half of it.
+    pub ttl_secs: u64,
+}
+
+impl TimeLimitedTurn {
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs((self.ttl_secs / 2).max(1))
+    }
+
+    /// The server with a credential valid for `ttl_secs` from now.
+    pub fn server(&self) -> Result<TurnServer, anyhow::Error> {
+        let expiry = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + self.ttl_secs;
+        let (username, credential) = credentials(&self.secret, &self.username, expiry)?;
+        Ok(TurnServer {
+            url: self.url.clone(),
+            username,
+            credential,
+        })
+    }
+}
+
+/// `expiry:username` and the base64 HMAC-SHA1 of it keyed with `secret`,
+/// `expiry` being the unix timestamp the credential stops being accepted at.
+pub fn credentials(
+    secret: &str,
+    username: &str,
+    expiry: u64,
+) -> Result<(String, String), anyhow::Error> {
+    let username = format!("{}:{}", expiry, username);
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(username.as_bytes())?;
+    let credential = base64::encode(signer.sign_to_vec()?);
+
+    Ok((username, credential))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_match_coturn() {
+        // echo -n "1433895918:webrtc" | openssl dgst -sha1 -hmac north -binary | base64
+        let (username, credential) = credentials("north", "webrtc", 1433895918).unwrap();
+        assert_eq!(username, "1433895918:webrtc");
+        assert_eq!(credential, "hIKsoxtt6rPYdH6Ds0zxcRL7kLA=");
+    }
+
+    #[test]
+    fn refresh_interval_is_half_the_ttl() {
+        let turn = TimeLimitedTurn {
+            url: "turn:turn.example.com:3478".to_owned(),
+            username: "webrtc".to_owned(),
+            secret: "north".to_owned(),
+            ttl_secs: 600,
+        };
+        assert_eq!(turn.refresh_interval(), Duration::from_secs(300));
+
+        let turn = TimeLimitedTurn { ttl_secs: 1, ..turn };
+        assert_eq!(turn.refresh_interval(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,626 @@
+use anyhow::{bail, Context};
+use gst_sdp::{SDPBandwidth, SDPMediaRef, SDPMessage, SDPMessageRef};
+use gst_webrtc::WebRTCSDPType;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{codec::VideoCodec, signaling};
+
+/// `RTCSessionDescriptionInit` as the browser's `JSON.stringify` writes it.
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionDescriptionInit {
+    #[serde(rename = "type")]
+    type_: String,
+    sdp: String,
+}
+
+/// Base64 of `{"type": ..., "sdp": ...}`, the form descriptions are pasted
+/// between terminal and browser in.
+pub fn serialize(type_: WebRTCSDPType, sdp: &SDPMessageRef) -> Result<String, anyhow::Error> {
+    let type_ = signaling::sdp_type_name(type_)
+        .ok_or_else(|| anyhow::anyhow!("unknown SDP type {:?}", type_))?;
+    let json = serde_json::to_string(&SessionDescriptionInit {
+        type_: type_.to_string(),
+        sdp: sdp.as_text()?,
+    })?;
+    Ok(base64::encode(json))
+}
+
+/// Inverse of `serialize`.
+pub fn deserialize(b64: &str) -> Result<(WebRTCSDPType, SDPMessage), anyhow::Error> {
+    let json = base64::decode(b64.trim()).context("description isn't base64")?;
+    let desc = serde_json::from_slice::<SessionDescriptionInit>(&json)
+        .context("description isn't a {type, sdp} object")?;
+    let type_ = signaling::sdp_type(&desc.type_)
+        .ok_or_else(|| anyhow::anyhow!("unknown SDP type \"{}\"", desc.type_))?;
+    let sdp = SDPMessage::parse_buffer(desc.sdp.as_bytes())
+        .map_err(|_| anyhow::anyhow!("couldn't parse {} SDP", desc.type_))?;
+    Ok((type_, sdp))
+}
+
+/// Adds an `a=group:LS` line covering every audio and video media section
+/// when both kinds are present and webrtcbin didn't emit one itself.
+///
+/// The group only tells the browser which streams to synchronize; the actual
+/// alignment comes from the RTCP sender reports. For those to line up, the
+/// audio and video branches must be timestamped against the same pipeline
+/// clock and base time, so both sources have to live in the same pipeline.
+pub fn ensure_lip_sync_group(sdp: &mut SDPMessageRef) -> Result<(), anyhow::Error> {
+    let has_ls_group = sdp.attributes().any(|attr| {
+        attr.key() == "group" && attr.value().map_or(false, |val| val.starts_with("LS"))
+    });
+    if has_ls_group {
+        return Ok(());
+    }
+
+    let mut has_audio = false;
+    let mut has_video = false;
+    let mut mids = Vec::new();
+    for media in sdp.medias() {
+        match media.media() {
+            Some("audio") => has_audio = true,
+            Some("video") => has_video = true,
+            _ => continue,
+        }
+        if let Some(mid) = media.attribute_val("mid") {
+            mids.push(mid.to_owned());
+        }
+    }
+
+    if has_audio && has_video {
+        sdp.add_attribute("group", Some(&format!("LS {}", mids.join(" "))))?;
+    }
+
+    Ok(())
+}
+
+/// Mid of every media section, indexed by mline.
+pub fn media_mids(sdp: &SDPMessageRef) -> Vec<String> {
+    sdp.medias()
+        .enumerate()
+        .map(|(idx, media)| {
+            media
+                .attribute_val("mid")
+                .map(String::from)
+                .unwrap_or_else(|| idx.to_string())
+        })
+        .collect()
+}
+
+/// Copy of `sdp` with the `profile-level-id` of every H264 format replaced.
+pub fn set_h264_profile_level_id(
+    sdp: &SDPMessageRef,
+    profile_level_id: &str,
+) -> Result<SDPMessage, anyhow::Error> {
+    // Attributes can only be replaced by index, so go through the text
+    let text = sdp.as_text()?;
+    let mut replaced = String::with_capacity(text.len());
+    for line in text.lines() {
+        // a=fmtp:<payload> key=value;key=value, only H264 has profile-level-id
+        match line.strip_prefix("a=fmtp:").and_then(|fmtp| fmtp.split_once(' ')) {
+            Some((payload, params)) => {
+                let params = params
+                    .split(';')
+                    .map(|param| match param.trim().split_once('=') {
+                        Some((key, _)) if key.eq_ignore_ascii_case("profile-level-id") => {
+                            format!("{}={}", key, profile_level_id)
+                        }
+                        _ => param.to_owned(),
+                    })
+                    .collect::<Vec<_>>();
+                replaced.push_str(&format!("a=fmtp:{} {}\r\n", payload, params.join(";")));
+            }
+            None => {
+                replaced.push_str(line);
+                replaced.push_str("\r\n");
+            }
+        }
+    }
+
+    SDPMessage::parse_buffer(replaced.as_bytes())
+        .map_err(|_| anyhow::anyhow!("couldn't parse SDP with new profile-level-id"))
+}
+
+/// Mid for webrtcbin's mline index out of `media_mids`.
+///
+/// When everything is bundled the candidates are only gathered for the
+/// first transport, so an out of range index falls back to the first mid.
+pub fn mid_for_mline(mids: &[String], mlineindex: u32) -> Option<String> {
+    mids.get(mlineindex as usize)
+        .or_else(|| mids.first())
+        .cloned()
+}
+
+/// Adds `a=rtcp-mux` to every media section that doesn't advertise it yet.
+pub fn ensure_rtcp_mux(sdp: &mut SDPMessageRef) -> Result<(), anyhow::Error> {
+    for idx in 0..sdp.medias_len() {
+        if let Some(media) = sdp.media_mut(idx) {
+            // Flag attributes have no value, so attribute_val can't tell them apart
+            if !media.attributes().any(|attr| attr.key() == "rtcp-mux") {
+                media.add_attribute("rtcp-mux", None)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Declares the simulcast layers on every video media section, webrtcbin
+/// doesn't know about rids itself. `rids` are listed in order of preference.
+pub fn add_simulcast(sdp: &mut SDPMessageRef, rids: &[&str]) -> Result<(), anyhow::Error> {
+    for idx in 0..sdp.medias_len() {
+        if let Some(media) = sdp.media_mut(idx) {
+            if media.media() != Some("video") {
+                continue;
+            }
+            for rid in rids {
+                media.add_attribute("rid", Some(&format!("{} send", rid)))?;
+            }
+            media.add_attribute("simulcast", Some(&format!("send {}", rids.join(";"))))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `profile-level-id` of every H264 format in the video media sections.
+pub fn h264_profile_level_ids(sdp: &SDPMessageRef) -> Vec<String> {
+    let mut ids = Vec::new();
+    for media in sdp.medias().filter(|media| media.media() == Some("video")) {
+        // a=rtpmap:<payload> H264/90000
+        let payloads = media
+            .attributes()
+            .filter(|attr| attr.key() == "rtpmap")
+            .filter_map(|attr| attr.value()?.split_once(' '))
+            .filter(|(_, codec)| codec.to_ascii_uppercase().starts_with("H264/"))
+            .map(|(payload, _)| payload)
+            .collect::<Vec<_>>();
+
+        // a=fmtp:<payload> key=value;key=value
+        for (payload, params) in media
+            .attributes()
+            .filter(|attr| attr.key() == "fmtp")
+            .filter_map(|attr| attr.value()?.split_once(' '))
+        {
+            if !payloads.contains(&payload) {
+                continue;
+            }
+            ids.extend(
+                params
+                    .split(';')
+                    .filter_map(|param| param.trim().split_once('='))
+                    .filter(|(key, _)| key.eq_ignore_ascii_case("profile-level-id"))
+                    .map(|(_, value)| value.to_ascii_lowercase()),
+            );
+        }
+    }
+
+    ids
+}
+
+/// Caps for a transceiver's `codec-preferences` listing the video payloads
+/// of `sdp` in the order of `preference`, matched by encoding name. `None`
+/// when the offer has none of them.
+pub fn codec_preferences(sdp: &SDPMessageRef, preference: &[String]) -> Option<gst::Caps> {
+    let media = sdp
+        .medias()
+        .find(|media| media.media() == Some("video") && media.port() != 0)?;
+
+    // a=rtpmap:<payload> <encoding name>/<clock rate>
+    let payloads = media
+        .attributes()
+        .filter(|attr| attr.key() == "rtpmap")
+        .filter_map(|attr| attr.value()?.split_once(' '))
+        .filter_map(|(payload, codec)| {
+            let mut fields = codec.split('/');
+            let name = fields.next()?;
+            let clock_rate = fields.next()?.parse::<i32>().ok()?;
+            Some((payload.parse::<i32>().ok()?, name, clock_rate))
+        })
+        .collect::<Vec<_>>();
+
+    let mut caps = gst::Caps::new_empty();
+    {
+        let caps = caps.get_mut().unwrap();
+        for name in preference {
+            for (payload, encoding_name, clock_rate) in payloads
+                .iter()
+                .filter(|(_, encoding_name, _)| encoding_name.eq_ignore_ascii_case(name))
+            {
+                caps.append_structure(
+                    gst::Structure::builder("application/x-rtp")
+                        .field("media", &"video")
+                        .field("encoding-name", &encoding_name.to_ascii_uppercase())
+                        .field("payload", payload)
+                        .field("clock-rate", clock_rate)
+                        .build(),
+                );
+            }
+        }
+    }
+
+    if caps.is_empty() {
+        None
+    } else {
+        Some(caps)
+    }
+}
+
+/// Bandwidth the remote allows for video in kbps, from the video media's
+/// `b=` lines or, failing that, the session level ones.
+pub fn video_bandwidth_kbps(sdp: &SDPMessageRef) -> Option<u32> {
+    fn to_kbps(bandwidth: &SDPBandwidth) -> Option<u32> {
+        match bandwidth.bwtype() {
+            Some("AS") => Some(bandwidth.value()),
+            Some("TIAS") => Some(bandwidth.value() / 1000),
+            _ => None,
+        }
+    }
+
+    sdp.medias()
+        .filter(|media| media.media() == Some("video"))
+        .flat_map(|media| media.bandwidths().filter_map(to_kbps).collect::<Vec<_>>())
+        .min()
+        .or_else(|| sdp.bandwidths().filter_map(to_kbps).min())
+}
+
+/// Checks `candidate` is a well-formed RFC 5245 candidate line, with or
+/// without the `candidate:` prefix.
+pub fn validate_candidate(candidate: &str) -> Result<(), anyhow::Error> {
+    let line = candidate.trim();
+    let line = line.strip_prefix("a=").unwrap_or(line);
+    let line = line.strip_prefix("candidate:").unwrap_or(line);
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    // foundation component transport priority address port typ type
+    if fields.len() < 8 {
+        bail!("candidate \"{}\" has too few fields", candidate);
+    }
+    if fields[1].parse::<u32>().is_err() || fields[3].parse::<u32>().is_err() {
+        bail!("candidate \"{}\" has a malformed component or priority", candidate);
+    }
+    if !fields[2].eq_ignore_ascii_case("udp") && !fields[2].eq_ignore_ascii_case("tcp") {
+        bail!("candidate \"{}\" has unknown transport {}", candidate, fields[2]);
+    }
+    if fields[5].parse::<u16>().is_err() {
+        bail!("candidate \"{}\" has a malformed port", candidate);
+    }
+    if fields[6] != "typ" || !matches!(fields[7], "host" | "srflx" | "prflx" | "relay") {
+        bail!("candidate \"{}\" has no valid type", candidate);
+    }
+
+    Ok(())
+}
+
+/// Copy of `sdp` with the media sections webrtcbin can't handle rejected:
+/// video that offers neither `video_codec` nor a codec of `codec_preference`,
+/// audio without Opus, non-SCTP data and any other media kind.
+///
+/// Rejected sections keep their m-line with the port set to 0, so the mline
+/// indexes of the answer still match the offer (RFC 3264, section 6). Their
+/// mids are removed from `a=group` lines.
+pub fn sanitize_sdp(
+    sdp: &SDPMessageRef,
+    video_codec: VideoCodec,
+    codec_preference: &[String],
+) -> Result<SDPMessage, anyhow::Error> {
+    let mut rejected = Vec::new();
+    let mut rejected_mids = Vec::new();
+    for (idx, media) in sdp.medias().enumerate() {
+        if media.port() == 0 || is_supported_media(media, video_codec, codec_preference) {
+            continue;
+        }
+        warn!(
+            mline = idx,
+            media = ?media.media(),
+            formats = ?media.formats().collect::<Vec<_>>(),
+            "rejecting unsupported media section"
+        );
+        rejected.push(idx);
+        rejected_mids.extend(media.attribute_val("mid").map(String::from));
+    }
+    if rejected.is_empty() {
+        return Ok(sdp.to_owned());
+    }
+
+    // Session level attributes can only be replaced by index, so go through the text
+    let text = sdp.as_text()?;
+    let mut sanitized = String::with_capacity(text.len());
+    let mut mline = None;
+    for line in text.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            let idx = mline.map_or(0, |idx| idx + 1);
+            mline = Some(idx);
+            if rejected.contains(&idx) {
+                // m=<media> <port>[/<number of ports>] <proto> <fmt> ...
+                let mut fields = media.splitn(3, ' ');
+                let kind = fields.next().unwrap_or_default();
+                let _port = fields.next();
+                let rest = fields.next().unwrap_or_default();
+                sanitized.push_str(&format!("m={} 0 {}\r\n", kind, rest));
+                continue;
+            }
+        }
+
+        match line.strip_prefix("a=group:") {
+            Some(group) => {
+                let mut fields = group.split_whitespace();
+                let semantics = fields.next().unwrap_or_default();
+                let mids = fields
+                    .filter(|mid| !rejected_mids.iter().any(|rejected| rejected == mid))
+                    .collect::<Vec<_>>();
+                if !mids.is_empty() {
+                    sanitized.push_str(&format!("a=group:{} {}\r\n", semantics, mids.join(" ")));
+                }
+            }
+            None => {
+                sanitized.push_str(line);
+                sanitized.push_str("\r\n");
+            }
+        }
+    }
+
+    SDPMessage::parse_buffer(sanitized.as_bytes())
+        .map_err(|_| anyhow::anyhow!("couldn't parse sanitized SDP"))
+}
+
+fn is_supported_media(
+    media: &SDPMediaRef,
+    video_codec: VideoCodec,
+    codec_preference: &[String],
+) -> bool {
+    // a=rtpmap:<payload> <encoding name>/<clock rate>[/<channels>]
+    let offers = |encoding_name: &str| {
+        media
+            .attributes()
+            .filter(|attr| attr.key() == "rtpmap")
+            .filter_map(|attr| attr.value())
+            .filter_map(|val| val.split_whitespace().nth(1))
+            .filter_map(|codec| codec.split('/').next())
+            .any(|name| name.eq_ignore_ascii_case(encoding_name))
+    };
+
+    match media.media() {
+        Some("video") => {
+            offers(video_codec.encoding_name())
+                || codec_preference
+                    .iter()
+                    .filter(|name| VideoCodec::from_encoding_name(name).is_some())
+                    .any(|name| offers(name))
+        }
+        Some("audio") => offers("OPUS"),
+        Some("application") => media.proto().map_or(false, |proto| proto.contains("SCTP")),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+o=- 0 0 IN IP4 0.0.0.0\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE video0 audio1\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=mid:video0\r\n\
+a=rtpmap:96 H264/90000\r\n\
+a=fmtp:96 packetization-mode=1;profile-level-id=42e01f\r\n\
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=mid:audio1\r\n\
+a=rtpmap:111 OPUS/48000/2\r\n";
+
+    fn parse(sdp: &str) -> SDPMessage {
+        gst::init().unwrap();
+        SDPMessage::parse_buffer(sdp.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn sanitize_rejects_unsupported_media_with_port_zero() {
+        // A second audio section that only offers PCMU, which webrtcbin isn't set up for
+        let offer = OFFER.replace("BUNDLE video0 audio1", "BUNDLE video0 audio1 audio2")
+            + "m=audio 9 UDP/TLS/RTP/SAVPF 0\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=mid:audio2\r\n\
+a=rtpmap:0 PCMU/8000\r\n";
+        let sanitized = sanitize_sdp(&parse(&offer), VideoCodec::H264, &[]).unwrap();
+
+        assert_eq!(sanitized.medias_len(), 3);
+        assert_eq!(sanitized.media(0).unwrap().port(), 9);
+        assert_eq!(sanitized.media(1).unwrap().port(), 9);
+        let rejected = sanitized.media(2).unwrap();
+        assert_eq!(rejected.media(), Some("audio"));
+        assert_eq!(rejected.port(), 0);
+        assert_eq!(rejected.attribute_val("mid"), Some("audio2"));
+
+        assert_eq!(sanitized.attribute_val("group"), Some("BUNDLE video0 audio1"));
+        assert_eq!(media_mids(&sanitized), vec!["video0", "audio1", "audio2"]);
+    }
+
+    #[test]
+    fn sanitize_keeps_supported_media() {
+        let offer = parse(OFFER);
+        let sanitized = sanitize_sdp(&offer, VideoCodec::H264, &[]).unwrap();
+        assert_eq!(sanitized.as_text().unwrap(), offer.as_text().unwrap());
+    }
+
+    #[test]
+    fn sanitize_keeps_video_with_a_preferred_codec() {
+        let offer = parse(&OFFER.replace(
+            "a=rtpmap:96 H264/90000\r\na=fmtp:96 packetization-mode=1;profile-level-id=42e01f",
+            "a=rtpmap:96 VP8/90000",
+        ));
+
+        let sanitized = sanitize_sdp(&offer, VideoCodec::H264, &[]).unwrap();
+        assert_eq!(sanitized.media(0).unwrap().port(), 0);
+        assert!(codec_preferences(&sanitized, &["VP8".into()]).is_none());
+
+        let preference = ["VP8".to_string()];
+        let sanitized = sanitize_sdp(&offer, VideoCodec::H264, &preference).unwrap();
+        assert_eq!(sanitized.media(0).unwrap().port(), 9);
+        let caps = codec_preferences(&sanitized, &preference).unwrap();
+        let s = caps.structure(0).unwrap();
+        assert_eq!(s.get::<String>("encoding-name").unwrap(), "VP8");
+        assert_eq!(s.get::<i32>("payload").unwrap(), 96);
+    }
+
+    #[test]
+    fn h264_profile_level_id_is_replaced() {
+        let answer = parse(&OFFER.replace("profile-level-id=42e01f", "profile-level-id=42c015"));
+        let answer = set_h264_profile_level_id(&answer, "42e01f").unwrap();
+        assert_eq!(h264_profile_level_ids(&answer), vec!["42e01f"]);
+        assert_eq!(
+            answer.media(0).unwrap().attribute_val("fmtp"),
+            Some("96 packetization-mode=1;profile-level-id=42e01f")
+        );
+    }
+
+    #[test]
+    fn rtcp_mux_is_added_once() {
+        let mut sdp = parse(&OFFER.replace(
+            "a=mid:video0\r\n",
+            "a=mid:video0\r\na=rtcp-mux\r\n",
+        ));
+        ensure_rtcp_mux(&mut sdp).unwrap();
+
+        for media in sdp.medias() {
+            let count = media.attributes().filter(|attr| attr.key() == "rtcp-mux").count();
+            assert_eq!(count, 1, "{:?}", media.attribute_val("mid"));
+        }
+    }
+
+    #[test]
+    fn mid_for_mline_uses_the_mid_attribute() {
+        let mids = media_mids(&parse(OFFER));
+        assert_eq!(mids, vec!["video0", "audio1"]);
+        assert_eq!(mid_for_mline(&mids, 0).as_deref(), Some("video0"));
+        assert_eq!(mid_for_mline(&mids, 1).as_deref(), Some("audio1"));
+        // Bundled candidates are reported against the first transport
+        assert_eq!(mid_for_mline(&mids, 5).as_deref(), Some("video0"));
+        assert_eq!(mid_for_mline(&[], 0), None);
+    }
+
+    #[test]
+    fn lip_sync_group_covers_audio_and_video() {
+        let mut answer = parse(OFFER);
+        ensure_lip_sync_group(&mut answer).unwrap();
+
+        let groups = answer
+            .attributes()
+            .filter(|attr| attr.key() == "group")
+            .filter_map(|attr| attr.value())
+            .collect::<Vec<_>>();
+        assert_eq!(groups, vec!["BUNDLE video0 audio1", "LS video0 audio1"]);
+
+        // A second pass finds the group and leaves it alone
+        ensure_lip_sync_group(&mut answer).unwrap();
+        let count = answer.attributes().filter(|attr| attr.key() == "group").count();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn lip_sync_group_needs_audio_and_video() {
+        let video_only = OFFER.split("m=audio").next().unwrap();
+        let mut answer = parse(video_only);
+        ensure_lip_sync_group(&mut answer).unwrap();
+        assert_eq!(answer.attribute_val("group"), Some("BUNDLE video0 audio1"));
+        assert_eq!(answer.attributes().filter(|attr| attr.key() == "group").count(), 1);
+    }
+
+    #[test]
+    fn media_mids_fall_back_to_the_mline_index() {
+        let sdp = parse(&OFFER.replace("a=mid:audio1\r\n", ""));
+        assert_eq!(media_mids(&sdp), vec!["video0", "1"]);
+    }
+
+    #[test]
+    fn video_bandwidth_from_b_lines() {
+        assert_eq!(video_bandwidth_kbps(&parse(OFFER)), None);
+
+        let video = "m=video 9 UDP/TLS/RTP/SAVPF 96\r\nc=IN IP4 0.0.0.0\r\n";
+        let offer = parse(&OFFER.replace(video, &format!("{}b=AS:500\r\n", video)));
+        assert_eq!(video_bandwidth_kbps(&offer), Some(500));
+
+        let offer = parse(&OFFER.replace(video, &format!("{}b=TIAS:800000\r\n", video)));
+        assert_eq!(video_bandwidth_kbps(&offer), Some(800));
+
+        // Audio limits don't apply to the video, the session level one does
+        let audio = "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\nc=IN IP4 0.0.0.0\r\n";
+        let offer = parse(
+            &OFFER
+                .replace(audio, &format!("{}b=AS:64\r\n", audio))
+                .replace("s=-\r\n", "s=-\r\nb=AS:1200\r\n"),
+        );
+        assert_eq!(video_bandwidth_kbps(&offer), Some(1200));
+    }
+
+    #[test]
+    fn candidates_are_validated() {
+        let host = "candidate:1 1 UDP 2013266431 10.0.0.1 5000 typ host";
+        assert!(validate_candidate(host).is_ok());
+        assert!(validate_candidate(&format!("a={}", host)).is_ok());
+        assert!(validate_candidate(host.trim_start_matches("candidate:")).is_ok());
+        assert!(validate_candidate(
+            "candidate:2 1 tcp 1518280447 10.0.0.1 9 typ srflx raddr 0.0.0.0 rport 0 tcptype active"
+        )
+        .is_ok());
+
+        for malformed in &[
+            "",
+            "candidate:1 1 UDP 2013266431 10.0.0.1 5000",
+            "candidate:1 one UDP 2013266431 10.0.0.1 5000 typ host",
+            "candidate:1 1 SCTP 2013266431 10.0.0.1 5000 typ host",
+            "candidate:1 1 UDP 2013266431 10.0.0.1 70000 typ host",
+            "candidate:1 1 UDP 2013266431 10.0.0.1 5000 typ local",
+        ] {
+            assert!(validate_candidate(malformed).is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn simulcast_layers_are_declared_on_video() {
+        let mut offer = parse(OFFER);
+        add_simulcast(&mut offer, &["h", "l"]).unwrap();
+
+        let video = offer.media(0).unwrap();
+        let rids = video
+            .attributes()
+            .filter(|attr| attr.key() == "rid")
+            .filter_map(|attr| attr.value())
+            .collect::<Vec<_>>();
+        assert_eq!(rids, vec!["h send", "l send"]);
+        assert_eq!(video.attribute_val("simulcast"), Some("send h;l"));
+
+        let audio = offer.media(1).unwrap();
+        assert_eq!(audio.attribute_val("rid"), None);
+        assert_eq!(audio.attribute_val("simulcast"), None);
+    }
+
+    #[test]
+    fn h264_profile_level_ids_of_the_offer() {
+        // A second H264 format in upper case and a VP9 one, whose fmtp doesn't count
+        let offer = OFFER
+            .replace(
+                "m=video 9 UDP/TLS/RTP/SAVPF 96\r\n",
+                "m=video 9 UDP/TLS/RTP/SAVPF 96 97 98\r\n",
+            )
+            .replace(
+                "a=mid:video0\r\n",
+                "a=mid:video0\r\n\
+a=rtpmap:97 h264/90000\r\n\
+a=fmtp:97 profile-level-id=640C1F;packetization-mode=1\r\n\
+a=rtpmap:98 VP9/90000\r\n\
+a=fmtp:98 profile-id=0\r\n",
+            );
+        let offer = parse(&offer);
+
+        assert_eq!(h264_profile_level_ids(&offer), vec!["640c1f", "42e01f"]);
+        assert_eq!(
+            crate::codec::compatible_h264_profile_level_id(&h264_profile_level_ids(&offer)),
+            Some("42e01f")
+        );
+    }
+}
@@ -1,14 +1,19 @@
-use std::sync::Arc;
+use std::{future::Future, net::SocketAddr, sync::Arc};
 
+use anyhow::Context;
 use bastion::{
+    context::BastionContext,
+    distributor::Distributor,
+    message::MessageHandler,
     spawn,
     supervisor::{RestartPolicy, RestartStrategy, SupervisorRef},
 };
 use tokio::{net::UdpSocket, select};
+use tracing::{debug, error, info, warn};
 use webrtc::{
     api::{
         interceptor_registry::register_default_interceptors,
-        media_engine::{MediaEngine, MIME_TYPE_VP8},
+        media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9},
         APIBuilder,
     },
     ice_transport::{ice_connection_state::RTCIceConnectionState, ice_server::RTCIceServer},
@@ -17,32 +22,69 @@ use webrtc::{
         configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription,
     },
-    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    rtcp::{
+        packet::Packet,
+        receiver_report::ReceiverReport,
+        reception_report::ReceptionReport,
+        sender_report::SenderReport,
+    },
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+        RTCPFeedback,
+    },
     track::track_local::{
         track_local_static_rtp::TrackLocalStaticRTP, TrackLocal, TrackLocalWriter,
     },
     Error,
 };
 
-use crate::gstreamer_actor::GstreamerActor;
+use crate::{
+    codec::VideoCodec,
+    gstreamer_actor::GstreamerActor,
+    signaling::{self, JsonMsg},
+    stats::ReceptionStats,
+};
+
+/// RTP clock rate of the relayed video, for converting RTCP jitter.
+const VIDEO_CLOCK_RATE: f64 = 90_000.0;
 
 pub struct WebRtcActor;
 
 impl WebRtcActor {
-    pub fn run(parent: SupervisorRef, sdp: &str) {
+    /// Relays the RTP the local GStreamer pipeline sends to the `index`th
+    /// port from `base_addr`, which may be IPv4 or IPv6, to the peer that made
+    /// the `sdp` offer. The pipeline encodes and the track advertises
+    /// `video_codec`.
+    pub fn run(
+        parent: SupervisorRef,
+        sdp: &str,
+        base_addr: SocketAddr,
+        index: u16,
+        video_codec: VideoCodec,
+    ) {
+        let rtp_addr = match rtp_addr(base_addr, index) {
+            Ok(rtp_addr) => rtp_addr,
+            Err(err) => {
+                error!(?err, "couldn't run WebRTC actor");
+                return;
+            }
+        };
         let sdp = sdp.to_owned();
         parent
             .supervisor(|s| {
                 s.with_restart_strategy(
-                    RestartStrategy::default().with_restart_policy(RestartPolicy::Never), // .with_restart_policy(RestartPolicy::Tries(5))
-                                                                                          // .with_actor_restart_strategy(ActorRestartStrategy::Immediate),
+                    RestartStrategy::default().with_restart_policy(RestartPolicy::Never),
                 )
                 .children(|c| {
                     c.with_exec(move |ctx| {
-                        println!("WebRTC started");
+                        info!("WebRTC started");
                         let sdp = sdp.clone();
-                        GstreamerActor::run(ctx.supervisor().unwrap().supervisor(|s| s).unwrap());
-                        main_fn(sdp)
+                        GstreamerActor::run(
+                            ctx.supervisor().unwrap().supervisor(|s| s).unwrap(),
+                            rtp_addr,
+                            video_codec,
+                        );
+                        main_fn(ctx, sdp, rtp_addr, video_codec)
                     })
                 })
             })
@@ -50,14 +92,107 @@ impl WebRtcActor {
     }
 }
 
-async fn main_fn(sdp: String) -> Result<(), ()> {
+/// `base_addr` with its port moved up by `index`, one port per relay.
+fn rtp_addr(base_addr: SocketAddr, index: u16) -> Result<SocketAddr, anyhow::Error> {
+    let port = base_addr.port().checked_add(index).ok_or_else(|| {
+        anyhow::anyhow!("RTP port {} + {} is out of range", base_addr.port(), index)
+    })?;
+    let mut rtp_addr = base_addr;
+    rtp_addr.set_port(port);
+    Ok(rtp_addr)
+}
+
+async fn main_fn(
+    ctx: BastionContext,
+    sdp: String,
+    rtp_addr: SocketAddr,
+    video_codec: VideoCodec,
+) -> Result<(), ()> {
+    serve_offers(
+        sdp,
+        |sdp| async move { serve_offer(&sdp, rtp_addr, video_codec).await },
+        |msg| {
+            if let Err(err) = signal(msg) {
+                warn!(?err, "couldn't send signaling message");
+            }
+        },
+        || next_offer(&ctx),
+    )
+    .await
+}
+
+/// Serves `sdp` and, when that fails, reports the error to the peer with
+/// `signal` and serves the next offer instead, so a bad offer doesn't take
+/// the actor down.
+async fn serve_offers<S, SF, N, NF>(
+    mut sdp: String,
+    mut serve: S,
+    mut signal: impl FnMut(&JsonMsg),
+    mut next_offer: N,
+) -> Result<(), ()>
+where
+    S: FnMut(String) -> SF,
+    SF: Future<Output = Result<(), anyhow::Error>>,
+    N: FnMut() -> NF,
+    NF: Future<Output = Result<String, ()>>,
+{
+    loop {
+        match serve(sdp).await {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                error!(?err, "couldn't serve WebRTC offer");
+                signal(&JsonMsg::Error {
+                    code: signaling::ERROR_REJECTED,
+                    reason: err.to_string(),
+                });
+            }
+        }
+
+        sdp = next_offer().await?;
+    }
+}
+
+async fn next_offer(ctx: &BastionContext) -> Result<String, ()> {
+    loop {
+        let mut next = None;
+        MessageHandler::new(ctx.recv().await?).on_tell(|offer: String, _| next = Some(offer));
+        if let Some(offer) = next {
+            return Ok(offer);
+        }
+    }
+}
+
+/// Sends `msg` to the peer through the `signaling_webrtc` distributor.
+fn signal(msg: &JsonMsg) -> Result<(), anyhow::Error> {
+    let text = serde_json::to_string(msg)?;
+    debug!(%text, "sending signaling message");
+    Distributor::named("signaling_webrtc")
+        .tell_one(text)
+        .map_err(|err| anyhow::anyhow!("couldn't send signaling message: {:?}", err))
+}
+
+/// Decodes an offer in the base64 `{type, sdp}` form.
+fn parse_offer(sdp: &str) -> Result<RTCSessionDescription, anyhow::Error> {
+    let bdata = base64::decode(sdp).context("couldn't decode SDP")?;
+    let desc_data = String::from_utf8(bdata).context("couldn't create string from utf8")?;
+    serde_json::from_str::<RTCSessionDescription>(&desc_data).context("couldn't deserialize")
+}
+
+async fn serve_offer(
+    sdp: &str,
+    rtp_addr: SocketAddr,
+    video_codec: VideoCodec,
+) -> Result<(), anyhow::Error> {
+    let offer = parse_offer(sdp)?;
+
+    // Only the codec the pipeline encodes, so the answer can't pick another one
     let mut m = MediaEngine::default();
-    m.register_default_codecs()
-        .expect("couldn't register default codec");
+    m.register_codec(codec_parameters(video_codec), RTPCodecType::Video)
+        .context("couldn't register video codec")?;
 
     let mut registry = Registry::new();
     registry = register_default_interceptors(registry, &mut m)
-        .expect("couldn't register default interceptors");
+        .context("couldn't register default interceptors")?;
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
@@ -74,14 +209,11 @@ async fn main_fn(sdp: String) -> Result<(), ()> {
     let peer_connection = Arc::new(
         api.new_peer_connection(config)
             .await
-            .expect("couldn't create new peer connection"),
+            .context("couldn't create new peer connection")?,
     );
 
     let video_track = Arc::new(TrackLocalStaticRTP::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_VP8.to_owned(),
-            ..Default::default()
-        },
+        codec_capability(video_codec),
         "video".to_owned(),
         "webrtc-rs".to_owned(),
     ));
@@ -89,11 +221,18 @@ async fn main_fn(sdp: String) -> Result<(), ()> {
     let rtp_sender = peer_connection
         .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
         .await
-        .expect("couldn't add track");
+        .context("couldn't add track")?;
 
     spawn!(async move {
-        let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+        let distributor = Distributor::named("rtcp_relay");
+        while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+            for stats in reception_stats(&packets) {
+                debug!(?stats, "RTCP reception report");
+                if let Err(err) = distributor.tell_one(stats) {
+                    debug!(?err, "nobody listens for RTCP reception reports");
+                }
+            }
+        }
         Result::<(), ()>::Ok(())
     });
 
@@ -102,7 +241,7 @@ async fn main_fn(sdp: String) -> Result<(), ()> {
 
     peer_connection
         .on_ice_connection_state_change(Box::new(move |connection_state: RTCIceConnectionState| {
-            println!("Connection State has changed {}", connection_state);
+            info!(%connection_state, "ICE connection state changed");
             if connection_state == RTCIceConnectionState::Failed {
                 let _ = done_tx1.try_send(());
             }
@@ -114,10 +253,10 @@ async fn main_fn(sdp: String) -> Result<(), ()> {
 
     peer_connection
         .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-            println!("Peer Connection State has changed: {}", s);
+            info!(state = %s, "peer connection state changed");
 
             if s == RTCPeerConnectionState::Failed {
-                println!("Peer Connection has gone to failed exiting: Done forwarding");
+                warn!("peer connection failed, done forwarding");
                 let _ = done_tx2.try_send(());
             }
 
@@ -125,43 +264,37 @@ async fn main_fn(sdp: String) -> Result<(), ()> {
         }))
         .await;
 
-    let bdata = base64::decode(sdp).expect("couldn't decode SDP");
-    let desc_data = String::from_utf8(bdata).expect("couldn't create string from utf8");
-    let offer =
-        serde_json::from_str::<RTCSessionDescription>(&desc_data).expect("couldn't deserialize");
-
     peer_connection
         .set_remote_description(offer)
         .await
-        .expect("couldn't set remote description");
+        .context("couldn't set remote description")?;
 
     let answer = peer_connection
         .create_answer(None)
         .await
-        .expect("couldn't create answer");
+        .context("couldn't create answer")?;
 
     let mut gather_complete = peer_connection.gathering_complete_promise().await;
 
     peer_connection
         .set_local_description(answer)
         .await
-        .expect("couldn't set local description");
+        .context("couldn't set local description")?;
 
     let _ = gather_complete.recv().await;
 
-    if let Some(local_desc) = peer_connection.local_description().await {
-        let json_str = serde_json::to_string(&local_desc)
-            .expect("couldn't deserialize local description to string");
-        let b64 = base64::encode(&json_str);
-        println!("{}", json_str);
-        println!("{}", b64);
-    } else {
-        println!("generate local_description failed!");
-    }
+    let local_desc = peer_connection
+        .local_description()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("no local description after gathering"))?;
+    signal(&JsonMsg::Sdp {
+        type_: "answer".to_owned(),
+        sdp: local_desc.sdp,
+    })?;
 
-    let listener = UdpSocket::bind("127.0.0.1:5004")
+    let listener = UdpSocket::bind(rtp_addr)
         .await
-        .expect("couldn't bind to local udp socket");
+        .with_context(|| format!("couldn't bind to local udp socket {}", rtp_addr))?;
 
     let done_tx3 = done_tx.clone();
 
@@ -172,7 +305,7 @@ async fn main_fn(sdp: String) -> Result<(), ()> {
                 if Error::ErrClosedPipe == err {
                     // The peerConnection has been closed.
                 } else {
-                    println!("video_track write err: {}", err);
+                    error!(%err, "video_track write failed");
                 }
                 let _ = done_tx3.try_send(());
                 return;
@@ -180,20 +313,237 @@ async fn main_fn(sdp: String) -> Result<(), ()> {
         }
     });
 
-    println!("Press ctrl-c to stop");
+    info!("press ctrl-c to stop");
     select! {
         _ = done_rx.recv() => {
-            println!("received done signal!");
-        }
-        _ = tokio::signal::ctrl_c() => {
-            println!("");
+            info!("received done signal");
         }
+        _ = tokio::signal::ctrl_c() => {}
     };
 
     peer_connection
         .close()
         .await
-        .expect("couldn't close connection");
+        .context("couldn't close connection")?;
 
     Ok(())
 }
+
+fn mime_type(video_codec: VideoCodec) -> &'static str {
+    match video_codec {
+        VideoCodec::H264 => MIME_TYPE_H264,
+        VideoCodec::Vp8 => MIME_TYPE_VP8,
+        VideoCodec::Vp9 => MIME_TYPE_VP9,
+    }
+}
+
+/// What the relayed track carries, with the feedback webrtc-rs registers by default.
+fn codec_capability(video_codec: VideoCodec) -> RTCRtpCodecCapability {
+    let sdp_fmtp_line = match video_codec {
+        // The encoder's constrained baseline profile
+        VideoCodec::H264 => {
+            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"
+        }
+        VideoCodec::Vp8 => "",
+        VideoCodec::Vp9 => "profile-id=0",
+    };
+    let rtcp_feedback = [("goog-remb", ""), ("ccm", "fir"), ("nack", ""), ("nack", "pli")]
+        .iter()
+        .map(|(typ, parameter)| RTCPFeedback {
+            typ: typ.to_string(),
+            parameter: parameter.to_string(),
+        })
+        .collect();
+
+    RTCRtpCodecCapability {
+        mime_type: mime_type(video_codec).to_owned(),
+        clock_rate: VIDEO_CLOCK_RATE as u32,
+        channels: 0,
+        sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+        rtcp_feedback,
+    }
+}
+
+fn codec_parameters(video_codec: VideoCodec) -> RTCRtpCodecParameters {
+    RTCRtpCodecParameters {
+        capability: codec_capability(video_codec),
+        // webrtc-rs's defaults for these codecs
+        payload_type: match video_codec {
+            VideoCodec::H264 => 102,
+            VideoCodec::Vp8 => 96,
+            VideoCodec::Vp9 => 98,
+        },
+        ..Default::default()
+    }
+}
+
+/// Report blocks of the sender and receiver reports in a compound RTCP
+/// packet, everything else (SDES, BYE, feedback, unknown types) is skipped.
+fn reception_stats(packets: &[Box<dyn Packet + Send + Sync>]) -> Vec<ReceptionStats> {
+    packets
+        .iter()
+        .flat_map(|packet| {
+            let any = packet.as_any();
+            let reports: &[ReceptionReport] =
+                if let Some(report) = any.downcast_ref::<ReceiverReport>() {
+                    &report.reports
+                } else if let Some(report) = any.downcast_ref::<SenderReport>() {
+                    &report.reports
+                } else {
+                    &[]
+                };
+            reports.iter().map(|report| ReceptionStats {
+                ssrc: report.ssrc,
+                fraction_lost_pct: report.fraction_lost as f64 * 100.0 / 256.0,
+                total_lost: report.total_lost,
+                jitter_ms: report.jitter as f64 * 1000.0 / VIDEO_CLOCK_RATE,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+
+    use super::*;
+
+    #[test]
+    fn reception_stats_from_receiver_report() {
+        let report = ReceiverReport {
+            ssrc: 1,
+            reports: vec![ReceptionReport {
+                ssrc: 42,
+                // 64/256 of the packets since the last report
+                fraction_lost: 64,
+                total_lost: 17,
+                jitter: 900,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let pli = PictureLossIndication {
+            sender_ssrc: 1,
+            media_ssrc: 42,
+        };
+        let packets: Vec<Box<dyn Packet + Send + Sync>> = vec![Box::new(report), Box::new(pli)];
+
+        assert_eq!(
+            reception_stats(&packets),
+            vec![ReceptionStats {
+                ssrc: 42,
+                fraction_lost_pct: 25.0,
+                total_lost: 17,
+                jitter_ms: 10.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn reception_stats_from_sender_report() {
+        let report = SenderReport {
+            ssrc: 1,
+            reports: vec![ReceptionReport {
+                ssrc: 42,
+                fraction_lost: 0,
+                total_lost: 0,
+                jitter: 90,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let packets: Vec<Box<dyn Packet + Send + Sync>> = vec![Box::new(report)];
+
+        let stats = reception_stats(&packets);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].fraction_lost_pct, 0.0);
+        assert_eq!(stats[0].jitter_ms, 1.0);
+    }
+
+    #[test]
+    fn track_capability_per_codec() {
+        for (video_codec, mime) in [
+            (VideoCodec::H264, "video/H264"),
+            (VideoCodec::Vp8, "video/VP8"),
+            (VideoCodec::Vp9, "video/VP9"),
+        ] {
+            let track = TrackLocalStaticRTP::new(
+                codec_capability(video_codec),
+                "video".to_owned(),
+                "webrtc-rs".to_owned(),
+            );
+            assert_eq!(track.codec().mime_type, mime);
+            assert_eq!(track.codec().clock_rate, 90_000);
+
+            let mut m = MediaEngine::default();
+            m.register_codec(codec_parameters(video_codec), RTPCodecType::Video)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn rtp_port_per_index() {
+        let base: SocketAddr = "127.0.0.1:5004".parse().unwrap();
+        for index in 0..=250 {
+            let addr = rtp_addr(base, index).unwrap();
+            assert_eq!(addr.ip(), base.ip());
+            assert_eq!(addr.port(), 5004 + index);
+        }
+
+        let base: SocketAddr = "[::1]:5004".parse().unwrap();
+        assert_eq!(rtp_addr(base, 11).unwrap(), "[::1]:5015".parse().unwrap());
+    }
+
+    #[test]
+    fn rtp_port_overflow() {
+        let base: SocketAddr = "127.0.0.1:65500".parse().unwrap();
+        assert_eq!(rtp_addr(base, 35).unwrap().port(), u16::MAX);
+        assert!(rtp_addr(base, 36).is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_offer_is_reported_and_the_next_one_served() {
+        let offer = base64::encode(r#"{"type": "offer", "sdp": "v=0\r\n"}"#);
+        let mut served = Vec::new();
+        let mut signaled = Vec::new();
+        let mut offers = vec![offer.clone()];
+
+        let res = serve_offers(
+            "not an offer".to_owned(),
+            |sdp| {
+                let res = parse_offer(&sdp).map(|_| ());
+                served.push(sdp);
+                async move { res }
+            },
+            |msg| signaled.push(msg.clone()),
+            || {
+                let next = offers.pop().ok_or(());
+                async move { next }
+            },
+        )
+        .await;
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(served, vec!["not an offer".to_owned(), offer]);
+        assert_eq!(signaled.len(), 1);
+        assert!(matches!(
+            &signaled[0],
+            JsonMsg::Error { code: signaling::ERROR_REJECTED, reason } if !reason.is_empty()
+        ));
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_offers_run_out() {
+        let mut signaled = 0;
+        let res = serve_offers(
+            String::new(),
+            |_| async { Err(anyhow::anyhow!("bad offer")) },
+            |_| signaled += 1,
+            || async { Err(()) },
+        )
+        .await;
+
+        assert_eq!(res, Err(()));
+        assert_eq!(signaled, 1);
+    }
+}
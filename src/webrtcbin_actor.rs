@@ -1,27 +1,75 @@
-use std::sync::{Arc, Weak};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::bail;
 use bastion::{
-    blocking,
     context::BastionContext,
     distributor::Distributor,
     message::MessageHandler,
     run,
     supervisor::{ActorRestartStrategy, RestartPolicy, RestartStrategy, SupervisorRef},
 };
+use futures::StreamExt;
 use gst::{
     glib,
-    prelude::{Cast, ElementExtManual, ObjectExt, ToValue},
-    traits::{ElementExt, GstBinExt, GstObjectExt},
+    prelude::{
+        Cast, ElementExtManual, GObjectExtManualGst, IsA, ObjectExt, PadExtManual, ToValue,
+    },
+    traits::{ClockExt, ElementExt, GstBinExt, GstObjectExt, PadExt, PipelineExt},
+};
+use serde::Deserialize;
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    codec::{
+        self, OpusConfig, VideoCodec, X264Config, H264_PROFILE, RID_EXTENSION_ID, RID_EXTENSION_URI,
+        TWCC_EXTENSION_ID, TWCC_EXTENSION_URI,
+    },
+    conn::{Connection, ConnectionState, PeerConnectionState},
+    control::{self, ControlErrorCode, ControlRequest, ControlResponse, Envelope},
+    dtls::DtlsCertificate,
+    ice::{self, IceConfig, IceMode},
+    metrics,
+    signaling::{self, IceCandidate, JsonMsg},
+    stats::{BandwidthEstimate, CandidatePath, ConnectionQuality, PeerStats, QualityThresholds},
+    upgrade_weak, utils,
 };
-use serde_json::{json, Value};
-
-use crate::upgrade_weak;
 
 type SDPType = gst_webrtc::WebRTCSDPType;
 type SessionDescription = gst_webrtc::WebRTCSessionDescription;
 
-#[derive(Copy, Clone)]
+/// How long the ICE connection may stay `Disconnected` before it is restarted.
+const ICE_DISCONNECTED_GRACE: Duration = Duration::from_secs(5);
+/// Minimum time between two ICE restarts.
+const ICE_RESTART_INTERVAL: Duration = Duration::from_secs(10);
+/// How long `shutdown` waits for EOS to drain through the pipeline.
+const SHUTDOWN_EOS_TIMEOUT: Duration = Duration::from_secs(3);
+/// rids of the simulcast layers, full size first.
+const SIMULCAST_RIDS: [&str; 2] = ["h", "l"];
+/// How often the bandwidth estimate is posted when `bandwidth_estimation` is on,
+/// the same tick checks for a lingering ICE disconnect.
+const BWE_INTERVAL: Duration = Duration::from_secs(1);
+/// Restarts in a row after which a source that ends without producing a
+/// single buffer isn't recovering.
+const SOURCE_RESTART_ATTEMPTS: u32 = 3;
+/// How long a control request may take before the peer gets a timeout error.
+const CONTROL_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often `run` tries to reach Playing before giving up on the pipeline.
+const PLAYING_ATTEMPTS: u32 = 3;
+/// Pause between two of those attempts, after resetting to Null.
+const PLAYING_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Branch `set_preview` hangs off `video_tee`.
+const PREVIEW_BRANCH: &str =
+    "queue leaky=downstream ! decodebin ! videoconvert ! autovideosink sync=false";
+
+#[derive(Debug, Copy, Clone)]
 pub enum WebRTCBinActorType {
     Client,
     Server,
@@ -36,6 +84,310 @@ impl AsRef<str> for WebRTCBinActorType {
     }
 }
 
+impl WebRTCBinActorType {
+    /// The client offers whenever webrtcbin needs negotiating, the server
+    /// only answers. With both offering their offers would cross.
+    pub fn is_offerer(&self) -> bool {
+        matches!(self, Self::Client)
+    }
+}
+
+/// webrtcbin always multiplexes RTCP, so `Require` is the only policy.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RtcpMuxPolicy {
+    /// RTP and RTCP share one transport, every media section carries `a=rtcp-mux`.
+    Require,
+}
+
+/// Direction of the video transceiver.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransceiverMode {
+    SendOnly,
+    /// No video is encoded or sent, the remote's video is shown with
+    /// `incoming_video_sink`.
+    RecvOnly,
+    SendRecv,
+}
+
+impl TransceiverMode {
+    fn direction(&self) -> gst_webrtc::WebRTCRTPTransceiverDirection {
+        match self {
+            Self::SendOnly => gst_webrtc::WebRTCRTPTransceiverDirection::Sendonly,
+            Self::RecvOnly => gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly,
+            Self::SendRecv => gst_webrtc::WebRTCRTPTransceiverDirection::Sendrecv,
+        }
+    }
+}
+
+/// Rolling recording of the encoded video into `splitmuxsink` segments.
+///
+/// Segments are cut on keyframes, so their length is rounded up to the
+/// encoder's `keyframe-max-dist`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub max_size_time_secs: u64,
+    /// Oldest segments are deleted once this many exist, 0 keeps everything.
+    pub max_files: u32,
+}
+
+/// Where the outgoing video comes from.
+///
+/// Everything but the test pattern goes through `videoconvert ! videoscale`
+/// so it ends up in the configured raw format, compressed inputs are decoded
+/// with `decodebin` first.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VideoSource {
+    TestPattern,
+    V4l2 { device: String },
+    Rtsp { url: String },
+    File { path: PathBuf },
+}
+
+impl Default for VideoSource {
+    fn default() -> Self {
+        Self::TestPattern
+    }
+}
+
+impl VideoSource {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        // filesrc would only fail once the pipeline goes to Paused, with a far less obvious error
+        if let Self::File { path } = self {
+            if !path.is_file() {
+                bail!("video file {} doesn't exist", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Source segment of the launch string, producing raw video.
+    fn description(&self) -> String {
+        match self {
+            Self::TestPattern => "videotestsrc pattern=ball is-live=true".to_owned(),
+            Self::V4l2 { device } => {
+                format!("v4l2src device=\"{}\" ! videoconvert ! videoscale", device)
+            }
+            Self::Rtsp { url } => format!(
+                "rtspsrc location=\"{}\" latency=200 ! decodebin ! videoconvert ! videoscale",
+                url
+            ),
+            Self::File { path } => format!(
+                "filesrc location=\"{}\" ! decodebin ! videoconvert ! videoscale",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Raw video format produced by the source and shown for the incoming stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VideoConfig {
+    pub width: u32,
+    pub height: u32,
+    /// Frames per second forced onto the source with `videorate`, the source's
+    /// own rate passes through when unset.
+    pub framerate: Option<u32>,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 480,
+            framerate: None,
+        }
+    }
+}
+
+impl VideoConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        // I420 subsamples chroma by two in both directions
+        if self.width == 0 || self.width % 2 != 0 || self.height == 0 || self.height % 2 != 0 {
+            bail!(
+                "video size {}x{} must be a positive multiple of 2",
+                self.width,
+                self.height
+            );
+        }
+        if self.framerate == Some(0) {
+            bail!("framerate must be positive");
+        }
+
+        Ok(())
+    }
+
+    fn caps(&self) -> String {
+        format!("video/x-raw,width={},height={}", self.width, self.height)
+    }
+}
+
+/// Buffering of the incoming streams, lower is less delay, higher rides out
+/// more network jitter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LatencyConfig {
+    /// webrtcbin's jitterbuffer `latency` (GStreamer 1.18).
+    pub latency_ms: u32,
+    /// `max-size-time` of the queue in front of each incoming decoder.
+    pub queue_max_time_ms: u64,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        Self {
+            latency_ms: 200,
+            queue_max_time_ms: 1000,
+        }
+    }
+}
+
+impl LatencyConfig {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.latency_ms > 2000 {
+            bail!("latency {}ms must be within 0-2000ms", self.latency_ms);
+        }
+        if self.queue_max_time_ms > 2000 {
+            bail!("queue max time {}ms must be within 0-2000ms", self.queue_max_time_ms);
+        }
+
+        Ok(())
+    }
+
+    /// Queue segment of the incoming branches.
+    fn queue(&self) -> String {
+        format!(
+            "queue max-size-buffers=0 max-size-bytes=0 max-size-time={}",
+            self.queue_max_time_ms * 1_000_000
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub ice: IceConfig,
+    pub video_codec: VideoCodec,
+    /// Encoding names, e.g. `["H264", "VP8"]`, in the order the answer should
+    /// pick the incoming video codec from an offer. webrtcbin's own choice is
+    /// kept when empty or when the offer has none of them. Only the first
+    /// offered preference is negotiated, so it has to be `video_codec` unless
+    /// the video is receive only.
+    pub codec_preference: Vec<String>,
+    /// Initial encoder bitrate in kbps.
+    pub bitrate_kbps: u32,
+    /// Hard ceiling applied to the initial bitrate and every `set_bitrate` call.
+    pub max_bitrate_kbps: Option<u32>,
+    /// Most frames between two keyframes. Lower lets a receiver start
+    /// decoding sooner, higher saves bandwidth.
+    pub key_int_max: u32,
+    pub x264: X264Config,
+    pub video_source: VideoSource,
+    /// Restarts the source when it ends instead of letting the video stop,
+    /// see `restart_source`.
+    pub restart_source_on_eos: bool,
+    pub video: VideoConfig,
+    pub transceiver_mode: TransceiverMode,
+    pub latency: LatencyConfig,
+    /// Sink bin the decoded incoming video ends up in, e.g. `autovideosink`
+    /// or a `compositor`/recording chain.
+    pub incoming_video_sink: String,
+    /// Tiles every incoming video stream into a grid of `video` size in front
+    /// of `incoming_video_sink`, for sessions with several video transceivers
+    /// receiving. Each stream otherwise gets its own sink.
+    pub composite_incoming: bool,
+    /// Drop the oldest buffers instead of blocking upstream once the send queue is full.
+    pub video_queue_leaky: bool,
+    /// Upper bound on how much video the send queue holds, in milliseconds.
+    pub video_queue_max_time_ms: u64,
+    /// Adds an Opus audio track next to the video.
+    pub audio: bool,
+    /// Diagnostic only: the audio track carries an audible 440Hz sine instead
+    /// of silence, to verify the audio path. Implies `audio`.
+    pub audio_test_tone: bool,
+    pub opus: OpusConfig,
+    /// Negotiates transport-wide congestion control and posts a
+    /// `BandwidthEstimate` to the `bwe_{type}` distributor every `BWE_INTERVAL`.
+    /// The header extension and `twcc-stats` need GStreamer 1.20.
+    pub bandwidth_estimation: bool,
+    /// Limits `quality` buckets the stats into.
+    pub quality_thresholds: QualityThresholds,
+    /// Negotiates NACK feedback (`a=rtcp-fb:96 nack`) and an RTX payload type
+    /// for the video so lost packets are retransmitted. Trades latency for
+    /// fewer artifacts on lossy links.
+    pub enable_rtx: bool,
+    /// Sends a second, half size layer at a quarter of the bitrate next to the
+    /// full one, tagged with the `SIMULCAST_RIDS`. Needs GStreamer 1.22 and a
+    /// receiver that understands `a=simulcast`. `set_bitrate` and
+    /// `force_keyframe` only act on the full layer.
+    pub simulcast: bool,
+    pub rtcp_mux: RtcpMuxPolicy,
+    pub recording: Option<RecordingConfig>,
+    /// Decodes and shows the outgoing video locally so an operator can watch
+    /// what is sent, can also be toggled with `set_preview` at runtime.
+    pub local_preview: bool,
+    /// Fixed DTLS certificate, webrtcbin generates a new one per run when unset.
+    pub dtls_certificate: Option<DtlsCertificate>,
+    /// Opens a reliable, ordered "control" data channel to the peer. Messages
+    /// with a `request_id` are answered as `ControlRequest`s, everything else
+    /// goes to the `data_{type}` distributor.
+    pub control_channel: bool,
+    /// Debug only: directory `dump_sdp` writes the negotiated descriptions to.
+    /// Dumping is disabled when unset.
+    pub sdp_dump_dir: Option<PathBuf>,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            ice: IceConfig::default(),
+            video_codec: VideoCodec::Vp8,
+            codec_preference: Vec::new(),
+            bitrate_kbps: 256,
+            max_bitrate_kbps: None,
+            key_int_max: 10,
+            x264: X264Config::default(),
+            video_source: VideoSource::default(),
+            restart_source_on_eos: false,
+            video: VideoConfig::default(),
+            transceiver_mode: TransceiverMode::SendRecv,
+            latency: LatencyConfig::default(),
+            incoming_video_sink: "autovideosink".to_owned(),
+            composite_incoming: false,
+            video_queue_leaky: true,
+            video_queue_max_time_ms: 200,
+            audio: false,
+            audio_test_tone: false,
+            opus: OpusConfig::default(),
+            bandwidth_estimation: false,
+            quality_thresholds: QualityThresholds::default(),
+            enable_rtx: false,
+            simulcast: false,
+            rtcp_mux: RtcpMuxPolicy::Require,
+            recording: None,
+            local_preview: false,
+            dtls_certificate: None,
+            control_channel: false,
+            sdp_dump_dir: None,
+        }
+    }
+}
+
+impl PipelineConfig {
+    pub fn clamp_bitrate(&self, kbps: u32) -> u32 {
+        match self.max_bitrate_kbps {
+            Some(max) if kbps > max => {
+                warn!(requested_kbps = kbps, max_kbps = max, "bitrate exceeds ceiling, clamping");
+                max
+            }
+            _ => kbps,
+        }
+    }
+}
+
 // #[derive(Debug, Clone)]
 // pub struct SDPMessage(SessionDescription);
 
@@ -57,8 +409,44 @@ pub struct WebRTCPipelineWeak(Weak<WebRTCPipelineInner>);
 
 #[derive(Debug)]
 pub struct WebRTCPipelineInner {
+    type_: WebRTCBinActorType,
     pipeline: gst::Pipeline,
     webrtcbin: gst::Element,
+    conn: Connection,
+    config: PipelineConfig,
+    ready: AtomicBool,
+    /// Set from `create-offer` until the offer is the local description.
+    offer_pending: AtomicBool,
+    mids: Mutex<Vec<String>>,
+    started_at: Mutex<Option<Instant>>,
+    last_ice_restart: Mutex<Option<Instant>>,
+    /// When the ICE connection went `Disconnected`, cleared on any other state.
+    ice_disconnected_since: Mutex<Option<Instant>>,
+    /// Set by every buffer reaching the encoder, cleared by each source restart.
+    source_flowing: AtomicBool,
+    /// Source restarts since the last one that produced buffers.
+    empty_source_restarts: AtomicU32,
+    /// `(mline, candidate)` pairs already handed to webrtcbin, reset on ICE restart.
+    remote_candidates: Mutex<HashSet<(u32, String)>>,
+    data_channel: Mutex<Option<glib::Object>>,
+    /// Branch added by `start_recording` and the tee pad feeding it.
+    recorder: Mutex<Option<(gst::Bin, gst::Pad)>>,
+    /// Local preview branch and the `video_tee` pad feeding it.
+    preview: Mutex<Option<(gst::Bin, gst::Pad)>>,
+    /// Local description held back until ICE gathering completes, in `Full` mode.
+    pending_description: Mutex<Option<SDPType>>,
+    /// Streams tiled into `incoming_compositor`, in grid order.
+    incoming_videos: Mutex<Vec<IncomingVideo>>,
+    /// Offered H264 `profile-level-id` the answer names, see `check_h264_profile`.
+    h264_profile_level_id: Mutex<Option<String>>,
+}
+
+/// Decoding branch of a `composite_incoming` video stream.
+struct IncomingVideo {
+    /// webrtcbin's src pad the stream arrives on.
+    src: gst::Pad,
+    bin: gst::Bin,
+    compositor_pad: gst::Pad,
 }
 
 impl std::ops::Deref for WebRTCPipeline {
@@ -88,23 +476,48 @@ impl WebRTCPipeline {
 }
 
 impl WebRTCPipeline {
-    pub fn init(type_: &WebRTCBinActorType) -> Result<Self, anyhow::Error> {
-        let pipeline = match type_ {
-            &WebRTCBinActorType::Server => gst::parse_launch(
-                "webrtcbin name=webrtcbin stun-server=stun://stun.l.google.com:19302 
-                videotestsrc pattern=ball is-live=true ! video/x-raw,width=640,height=480,format=I420 ! 
-                vp8enc error-resilient=partitions keyframe-max-dist=10 auto-alt-ref=true cpu-used=5 deadline=1 ! 
-                rtpvp8pay ! webrtcbin.",
-            )
-            .expect("couldn't parse pipeline from string"),
-            &WebRTCBinActorType::Client => gst::parse_launch(
-                    "webrtcbin name=webrtcbin stun-server=stun://stun.l.google.com:19302 
-                videotestsrc pattern=ball is-live=true ! video/x-raw,width=640,height=480,format=I420 ! 
-                vp8enc error-resilient=partitions keyframe-max-dist=10 auto-alt-ref=true cpu-used=5 deadline=1 ! 
-                rtpvp8pay ! webrtcbin.",
-            )
-            .expect("couldn't parse pipeline from string"),
-        };
+    /// The exact `parse_launch` string `init` would use, for pasting into `gst-launch-1.0`.
+    pub fn launch_string(type_: &WebRTCBinActorType, config: &PipelineConfig) -> String {
+        pipeline_description(type_, config)
+    }
+
+    pub fn init(
+        type_: &WebRTCBinActorType,
+        config: &PipelineConfig,
+    ) -> Result<Self, anyhow::Error> {
+        config.video.validate()?;
+        config.video_source.validate()?;
+        config.opus.validate()?;
+        config.latency.validate()?;
+        if config.key_int_max == 0 {
+            bail!("key_int_max must be at least 1");
+        }
+        if config.video_codec == VideoCodec::H264 {
+            config.x264.validate()?;
+        }
+        config.quality_thresholds.validate()?;
+        if config.bandwidth_estimation && gst::version() < (1, 20, 0, 0) {
+            bail!("bandwidth estimation needs GStreamer 1.20 or newer");
+        }
+        if config.simulcast {
+            if config.transceiver_mode == TransceiverMode::RecvOnly {
+                bail!("simulcast needs outgoing video, it can't be used with RecvOnly");
+            }
+            if gst::version() < (1, 22, 0, 0) {
+                bail!("simulcast needs GStreamer 1.22 or newer");
+            }
+            // The half size layer has to stay a multiple of 2 as well
+            if config.video.width % 4 != 0 || config.video.height % 4 != 0 {
+                bail!("simulcast needs a video size that is a multiple of 4");
+            }
+        }
+        if config.transceiver_mode == TransceiverMode::RecvOnly && config.recording.is_some() {
+            bail!("recording needs outgoing video, it can't be used with RecvOnly");
+        }
+        config.ice.validate()?;
+
+        let pipeline = gst::parse_launch(&pipeline_description(type_, config))
+            .expect("couldn't parse pipeline from string");
 
         let pipeline = pipeline
             .downcast::<gst::Pipeline>()
@@ -112,34 +525,116 @@ impl WebRTCPipeline {
 
         let webrtcbin = pipeline.by_name("webrtcbin").expect("can't find webrtcbin");
 
+        // Set programmatically so a misspelled nick can't silently fall back to `none`
+        webrtcbin.set_property("bundle-policy", &gst_webrtc::WebRTCBundlePolicy::MaxBundle)?;
+        if webrtcbin.has_property("latency", None) {
+            webrtcbin.set_property("latency", &config.latency.latency_ms)?;
+        } else if config.latency != LatencyConfig::default() {
+            warn!("webrtcbin has no latency property before GStreamer 1.18, ignoring latency_ms");
+        }
+
+        if let Some(stun_server) = config.ice.stun_uri()? {
+            webrtcbin.set_property("stun-server", &stun_server)?;
+        }
+        for (server, uri) in config.ice.turn_servers.iter().zip(config.ice.turn_uris()?) {
+            let added = webrtcbin
+                .emit_by_name("add-turn-server", &[&uri])?
+                .and_then(|val| val.get::<bool>().ok())
+                .unwrap_or(false);
+            if !added {
+                bail!("webrtcbin rejected TURN server {}", server.url);
+            }
+        }
+        if let Some(turn) = &config.ice.time_limited_turn {
+            webrtcbin.set_property("turn-server", &ice::turn_uri(&turn.server()?)?)?;
+        }
+
+        if config.transceiver_mode == TransceiverMode::RecvOnly {
+            // Without a send chain there's no sink pad to create the transceiver from
+            let caps = gst::Caps::builder("application/x-rtp")
+                .field("media", &"video")
+                .field("encoding-name", &config.video_codec.encoding_name())
+                .field("payload", &96)
+                .field("clock-rate", &90000)
+                .build();
+            let direction = config.transceiver_mode.direction();
+            webrtcbin.emit_by_name("add-transceiver", &[&direction, &caps])?;
+        }
+
         if let Some(transceiver) = webrtcbin
             .emit_by_name("get-transceiver", &[&0.to_value()])
             .unwrap()
             .and_then(|val| val.get::<glib::Object>().ok())
         {
-            transceiver.set_property("do-nack", &false.to_value())?;
+            transceiver.set_property("do-nack", &config.enable_rtx.to_value())?;
+            transceiver.set_property("direction", &config.transceiver_mode.direction())?;
         }
 
         let pipeline = Self(Arc::new(WebRTCPipelineInner {
+            type_: *type_,
             pipeline,
+            conn: Connection::new(webrtcbin.clone()),
             webrtcbin,
+            config: config.clone(),
+            ready: AtomicBool::new(false),
+            offer_pending: AtomicBool::new(false),
+            mids: Mutex::new(Vec::new()),
+            started_at: Mutex::new(None),
+            last_ice_restart: Mutex::new(None),
+            ice_disconnected_since: Mutex::new(None),
+            source_flowing: AtomicBool::new(false),
+            empty_source_restarts: AtomicU32::new(0),
+            remote_candidates: Mutex::new(HashSet::new()),
+            data_channel: Mutex::new(None),
+            recorder: Mutex::new(None),
+            preview: Mutex::new(None),
+            pending_description: Mutex::new(None),
+            incoming_videos: Mutex::new(Vec::new()),
+            h264_profile_level_id: Mutex::new(None),
         }));
 
         let pl_clone = pipeline.downgrade();
-        pipeline
-            .webrtcbin
-            .connect("on-negotiation-needed", false, move |_| {
-                let pipeline = upgrade_weak!(pl_clone, None);
-                if let Err(err) = pipeline.on_negotiation_needed() {
-                    gst::element_error!(
-                        pipeline.pipeline,
-                        gst::LibraryError::Failed,
-                        ("Failed to negotiate: {:?}", err)
-                    );
+        pipeline.webrtcbin.connect_pad_added(move |_, pad| {
+            let pipeline = upgrade_weak!(pl_clone);
+            if let Err(err) = pipeline.on_incoming_stream(pad) {
+                gst::element_error!(
+                    pipeline.pipeline,
+                    gst::LibraryError::Failed,
+                    ("Failed to handle incoming stream: {:?}", err)
+                );
+            }
+        });
+
+        let pl_clone = pipeline.downgrade();
+        pipeline.webrtcbin.connect_pad_removed(move |_, pad| {
+            let pipeline = upgrade_weak!(pl_clone);
+            let pl_clone = pipeline.downgrade();
+            let pad = pad.clone();
+            pipeline.pipeline.call_async(move |_| {
+                let pipeline = upgrade_weak!(pl_clone);
+                if let Err(err) = pipeline.remove_incoming_video(&pad) {
+                    warn!(?err, ?pad, "couldn't remove incoming video from the compositor");
                 }
+            });
+        });
 
-                None
-            })?;
+        if type_.is_offerer() {
+            let pl_clone = pipeline.downgrade();
+            pipeline
+                .webrtcbin
+                .connect("on-negotiation-needed", false, move |_| {
+                    let pipeline = upgrade_weak!(pl_clone, None);
+                    if let Err(err) = pipeline.on_negotiation_needed() {
+                        gst::element_error!(
+                            pipeline.pipeline,
+                            gst::LibraryError::Failed,
+                            ("Failed to negotiate: {:?}", err)
+                        );
+                    }
+
+                    None
+                })?;
+        }
 
         let pl_clone = pipeline.downgrade();
         pipeline
@@ -162,270 +657,3185 @@ impl WebRTCPipeline {
             })
             .expect("couldn't connect webrtcbin to ice candidate process");
 
+        let pl_clone = pipeline.downgrade();
+        pipeline
+            .webrtcbin
+            .connect_notify(Some("connection-state"), move |webrtcbin, _| {
+                let pipeline = upgrade_weak!(pl_clone);
+                let state = webrtcbin
+                    .property("connection-state")
+                    .ok()
+                    .and_then(|val| val.get::<gst_webrtc::WebRTCPeerConnectionState>().ok());
+                if let Some(state) = state {
+                    pipeline.on_connection_state(state);
+                }
+            });
+
+        let pl_clone = pipeline.downgrade();
+        pipeline
+            .webrtcbin
+            .connect_notify(Some("ice-gathering-state"), move |webrtcbin, _| {
+                let pipeline = upgrade_weak!(pl_clone);
+                let state = webrtcbin
+                    .property("ice-gathering-state")
+                    .ok()
+                    .and_then(|val| val.get::<gst_webrtc::WebRTCICEGatheringState>().ok());
+                if state != Some(gst_webrtc::WebRTCICEGatheringState::Complete) {
+                    return;
+                }
+                if pipeline.config.ice.mode == IceMode::Full {
+                    pipeline.send_gathered_description();
+                } else if let Err(err) = pipeline.on_ice_candidate(0, String::new()) {
+                    warn!(?err, "couldn't send end of candidates");
+                }
+            });
+
+        let pl_clone = pipeline.downgrade();
+        pipeline
+            .webrtcbin
+            .connect_notify(Some("ice-connection-state"), move |_, _| {
+                let pipeline = upgrade_weak!(pl_clone);
+                match pipeline.ice_connection_state() {
+                    Some(gst_webrtc::WebRTCICEConnectionState::Failed) => {
+                        if let Err(err) = pipeline.request_ice_restart() {
+                            error!(actor = pipeline.type_.as_ref(), ?err, "couldn't restart ICE");
+                        }
+                    }
+                    Some(gst_webrtc::WebRTCICEConnectionState::Disconnected) => {
+                        // Disconnected often recovers on its own, the actor's tick
+                        // restarts ICE if it sticks
+                        let mut since = pipeline.ice_disconnected_since.lock().unwrap();
+                        since.get_or_insert_with(Instant::now);
+                        return;
+                    }
+                    _ => (),
+                }
+                *pipeline.ice_disconnected_since.lock().unwrap() = None;
+            });
+
+        if config.control_channel {
+            pipeline.create_control_channel()?;
+        }
+        if config.simulcast {
+            // The payloaders only create their rid extension once caps are negotiated
+            for (name, rid) in ["payloader", "payloader_low"].iter().zip(SIMULCAST_RIDS) {
+                let payloader = pipeline
+                    .pipeline
+                    .by_name(name)
+                    .ok_or_else(|| anyhow::anyhow!("couldn't find {}", name))?;
+                payloader.connect_notify(Some("extensions"), move |payloader, _| {
+                    set_rid(payloader, rid)
+                });
+            }
+        }
+        if config.local_preview {
+            pipeline.set_preview(true)?;
+        }
+
+        // rtpsession already turns incoming PLI/FIR into GstForceKeyUnit events
+        // that travel upstream to the encoder, this only makes them visible
+        if let Some(encoder_src) = pipeline
+            .pipeline
+            .by_name("encoder")
+            .and_then(|encoder| encoder.static_pad("src"))
+        {
+            let actor = type_.as_ref().to_owned();
+            encoder_src.add_probe(gst::PadProbeType::EVENT_UPSTREAM, move |_, info| {
+                if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                    if is_force_key_unit(event) {
+                        debug!(actor = actor.as_str(), "keyframe requested");
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+
+        if config.restart_source_on_eos {
+            // EOS must not reach webrtcbin, that would end the stream for the peer
+            for name in ["encoder", "encoder_low"] {
+                let encoder_sink = match pipeline
+                    .pipeline
+                    .by_name(name)
+                    .and_then(|encoder| encoder.static_pad("sink"))
+                {
+                    Some(pad) => pad,
+                    None => continue,
+                };
+                let pl_clone = pipeline.downgrade();
+                let probe_type = gst::PadProbeType::EVENT_DOWNSTREAM | gst::PadProbeType::BUFFER;
+                encoder_sink.add_probe(probe_type, move |_, info| {
+                    match &info.data {
+                        Some(gst::PadProbeData::Buffer(_)) => {
+                            let pipeline = upgrade_weak!(pl_clone, gst::PadProbeReturn::Ok);
+                            pipeline.source_flowing.store(true, Ordering::Relaxed);
+                            gst::PadProbeReturn::Ok
+                        }
+                        Some(gst::PadProbeData::Event(event))
+                            if event.type_() == gst::EventType::Eos =>
+                        {
+                            if name == "encoder" {
+                                let pipeline = upgrade_weak!(pl_clone, gst::PadProbeReturn::Drop);
+                                pipeline.on_source_eos();
+                            }
+                            gst::PadProbeReturn::Drop
+                        }
+                        _ => gst::PadProbeReturn::Ok,
+                    }
+                });
+            }
+        }
+
+        if let Some(certificate) = &config.dtls_certificate {
+            certificate.validate()?;
+            info!(
+                fingerprint = %certificate.fingerprint()?,
+                "using fixed DTLS certificate"
+            );
+
+            // webrtcbin has no certificate property, the pem has to go on the
+            // dtls decoders it creates for each transport
+            let pem = certificate.pem();
+            pipeline
+                .pipeline
+                .connect("deep-element-added", false, move |values| {
+                    let element = values[2].get::<gst::Element>().expect("invalid argument");
+                    let is_dtls_dec = element
+                        .factory()
+                        .map_or(false, |factory| factory.name() == "dtlssrtpdec");
+                    if is_dtls_dec {
+                        if let Err(err) = element.set_property("pem", &pem) {
+                            error!(?err, "couldn't set DTLS certificate");
+                        }
+                    }
+                    None
+                })?;
+        }
+
+        if let Some(recording) = &config.recording {
+            std::fs::create_dir_all(&recording.dir)?;
+
+            let recorder = pipeline
+                .pipeline
+                .by_name("recorder")
+                .expect("can't find recorder");
+            // MP4 can't carry VP8, Matroska can
+            recorder.set_property("muxer", &gst::ElementFactory::make("matroskamux", None)?)?;
+
+            let dir = recording.dir.clone();
+            let prefix = type_.as_ref().to_owned();
+            recorder.connect("format-location", false, move |_| {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or_default();
+                let location = dir.join(format!("{}-{}.mkv", prefix, timestamp));
+                Some(location.to_string_lossy().to_value())
+            })?;
+        }
+
         Ok(pipeline)
     }
 
-    pub fn run(&self) -> Result<(), anyhow::Error> {
-        self.pipeline.call_async(|pipeline| {
-            if pipeline.set_state(gst::State::Playing).is_err() {
-                gst::element_error!(
-                    pipeline,
-                    gst::LibraryError::Failed,
-                    ("Failed to set pipeline to Playing")
-                );
+    /// Sets the pipeline to Playing. A failed state change is retried after a
+    /// reset to Null, a device or port still held by the previous run frees
+    /// up shortly.
+    pub async fn run(&self) -> Result<(), anyhow::Error> {
+        *self.started_at.lock().unwrap() = Some(Instant::now());
+        self.conn.reset();
+        self.offer_pending.store(false, Ordering::SeqCst);
+
+        for attempt in 1..=PLAYING_ATTEMPTS {
+            match self.pipeline.set_state(gst::State::Playing) {
+                Ok(_) => return Ok(()),
+                Err(err) => warn!(attempt, ?err, "couldn't set pipeline to Playing"),
             }
-        });
+            let _ = self.pipeline.set_state(gst::State::Null);
+            if attempt < PLAYING_ATTEMPTS {
+                tokio::time::sleep(PLAYING_RETRY_DELAY).await;
+            }
+        }
+
+        bail!("couldn't set pipeline to Playing after {} attempts", PLAYING_ATTEMPTS)
+    }
+
+    /// Asks the encoder for a keyframe, with codec headers, right away.
+    pub fn force_keyframe(&self) -> Result<(), anyhow::Error> {
+        let pad = self
+            .pipeline
+            .by_name("encoder")
+            .and_then(|encoder| encoder.static_pad("src"))
+            .ok_or_else(|| anyhow::anyhow!("couldn't find encoder src pad"))?;
+        let event = gst::event::CustomUpstream::new(
+            gst::Structure::builder("GstForceKeyUnit")
+                .field("all-headers", &true)
+                .build(),
+        );
+        if !pad.send_event(event) {
+            bail!("encoder didn't handle keyframe request");
+        }
 
         Ok(())
     }
 
-    async fn handle_sdp(&self, type_: &SDPType, sdp: &str) -> Result<(), anyhow::Error> {
-        match type_ {
-            &SDPType::Answer => {
-                print!("Received answer:\n{}\n", sdp);
+    /// Drains the pipeline with EOS so the recording is finalized, then stops it.
+    ///
+    /// Gives up waiting after `SHUTDOWN_EOS_TIMEOUT`, the pipeline is stopped
+    /// either way and the peer sees the connection close. Calling it on a
+    /// stopped pipeline does nothing, and `run` starts it up again.
+    pub async fn shutdown(&self) -> Result<(), anyhow::Error> {
+        if self.pipeline.current_state() == gst::State::Null {
+            return Ok(());
+        }
 
-                let mut json_answer = serde_json::to_string(sdp)
-                    .expect("couldn't serialize local description to string");
-                json_answer = json!({
-                    "type": "answer",
-                    "sdp": json_answer
-                })
-                .to_string();
-                let b64 = base64::encode(&json_answer);
-                println!("{}", b64);
+        if self.pipeline.send_event(gst::event::Eos::new()) {
+            // The actor isn't draining the bus while it waits on this
+            let bus = self.pipeline.bus().unwrap();
+            let timeout = gst::ClockTime::from_mseconds(SHUTDOWN_EOS_TIMEOUT.as_millis() as u64);
+            bus.timed_pop_filtered(timeout, &[gst::MessageType::Eos, gst::MessageType::Error]);
+        }
 
-                let ret = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
-                    .map_err(|_| anyhow::anyhow!("Failed to parse SDP answer"))?;
+        self.pipeline.set_state(gst::State::Null)?;
+        self.data_channel.lock().unwrap().take();
+        // The bus isn't drained anymore, so the Null state change goes unseen
+        self.ready.store(false, Ordering::SeqCst);
+        metrics::set_pipeline_state(self.type_.as_ref(), gst::State::Null);
+        metrics::set_pipeline_ready(self.type_.as_ref(), self.is_ready());
+        metrics::set_peers_active(self.type_.as_ref(), 0);
+        self.conn.transition(ConnectionState::Closed)?;
 
-                let answer = SessionDescription::new(SDPType::Answer, ret);
+        Ok(())
+    }
 
-                self.webrtcbin
-                    .emit_by_name("set-remote-description", &[&answer, &None::<gst::Promise>])
-                    .expect("couldn't set remote description for webrtcbin");
+    /// Updates the encoder bitrate live, clamped to `max_bitrate_kbps`.
+    ///
+    /// Every pipeline owns its encoder and serves a single webrtcbin, so this
+    /// only affects this pipeline's peer. The recording branch sits behind the
+    /// same encoder and follows the change.
+    pub fn set_bitrate(&self, kbps: u32) -> Result<(), anyhow::Error> {
+        let kbps = self.config.clamp_bitrate(kbps);
+
+        let encoder = self
+            .pipeline
+            .by_name("encoder")
+            .ok_or_else(|| anyhow::anyhow!("couldn't find encoder"))?;
+        self.config.video_codec.set_bitrate(&encoder, kbps)
+    }
 
-                Ok(())
-            }
-            &SDPType::Offer => {
-                // println!("Received offer: \n{}\n", sdp);
+    /// Updates the encoder's keyframe interval live, see `VideoCodec::set_key_int_max`.
+    pub fn set_key_int_max(&self, frames: u32) -> Result<(), anyhow::Error> {
+        if frames == 0 {
+            bail!("key_int_max must be at least 1");
+        }
 
-                let b = base64::decode(sdp)?;
-                let offer_json: Value = serde_json::from_slice(&b).expect("couldn't deserialize");
-                let ret = gst_sdp::SDPMessage::parse_buffer(
-                    &offer_json["sdp"].as_str().unwrap().as_bytes(),
-                )?;
+        let encoder = self
+            .pipeline
+            .by_name("encoder")
+            .ok_or_else(|| anyhow::anyhow!("couldn't find encoder"))?;
+        self.config.video_codec.set_key_int_max(&encoder, frames)
+    }
 
-                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    pub async fn stats(&self) -> Result<PeerStats, anyhow::Error> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let promise = gst::Promise::with_change_func(move |reply| {
+            let _ = tx.send(match reply {
+                Ok(Some(reply)) => Ok(PeerStats::from_structure(reply)),
+                Ok(None) => Err(anyhow::anyhow!("get-stats got no response")),
+                Err(err) => Err(anyhow::anyhow!("get-stats got error response: {:?}", err)),
+            });
+        });
 
-                let pl_clone = self.downgrade();
-                self.pipeline.call_async(move |_| {
-                    let pipeline = upgrade_weak!(pl_clone);
-                    let offer = SessionDescription::new(SDPType::Offer, ret);
-                    pipeline
-                        .0
-                        .webrtcbin
-                        .emit_by_name("set-remote-description", &[&offer, &None::<gst::Promise>])
-                        .expect("couldn't set remote description for webrtcbin");
-
-                    let pl_clone = pipeline.downgrade();
-                    let promise = gst::Promise::with_change_func(move |reply| {
-                        let pipeline = upgrade_weak!(pl_clone);
-
-                        run! { async {
-                            if let Err(err) = pipeline.on_answer_created(reply).await {
-                                gst::element_error!(
-                                    pipeline.pipeline,
-                                    gst::LibraryError::Failed,
-                                    ("Failed to send SDP answer: {:?}", err)
-                                );
-                            }
-                        }}
-                    });
+        self.webrtcbin
+            .emit_by_name("get-stats", &[&None::<gst::Pad>, &promise])?;
 
-                    pipeline
-                        .0
-                        .webrtcbin
-                        .emit_by_name("create-answer", &[&None::<gst::Structure>, &promise])
-                        .expect("couldn't create answer for webrtcbin");
-                });
+        rx.await?
+    }
 
-                Ok(())
+    /// Publishes a `connection-state` change to the `conn_state_{type}`
+    /// distributor and follows it in `conn`.
+    fn on_connection_state(&self, state: gst_webrtc::WebRTCPeerConnectionState) {
+        let connected = state == gst_webrtc::WebRTCPeerConnectionState::Connected;
+        metrics::set_peers_active(self.type_.as_ref(), connected as u64);
+        let conn_state = Distributor::named(format!("conn_state_{}", self.type_.as_ref()));
+        let peer = self.type_.as_ref().to_owned();
+        if let Err(err) = conn_state.tell_one((peer, PeerConnectionState::from(state))) {
+            debug!(?err, "nobody listens for connection states");
+        }
+
+        let next = match state {
+            gst_webrtc::WebRTCPeerConnectionState::Connected => Some(ConnectionState::Connected),
+            gst_webrtc::WebRTCPeerConnectionState::Failed => Some(ConnectionState::Failed),
+            gst_webrtc::WebRTCPeerConnectionState::Closed => Some(ConnectionState::Closed),
+            _ => None,
+        };
+        if let Some(Err(err)) = next.map(|next| self.conn.transition(next)) {
+            warn!(actor = self.type_.as_ref(), ?err, "connection state out of sync");
+        }
+        if connected {
+            if let Some(started_at) = self.started_at.lock().unwrap().take() {
+                if let Err(err) = self.observe_time_to_connected(started_at.elapsed()) {
+                    warn!(?err, "couldn't record time to connected");
+                }
             }
-            _ => bail!("SDP type is not \"answer\" but \"{}\"", type_.to_str()),
         }
     }
 
-    fn on_ice_candidate(&self, mlineindex: u32, candidate: String) -> Result<(), anyhow::Error> {
+    /// Records `elapsed` under the path of the selected candidate pair, which
+    /// is only known from the stats once connected.
+    fn observe_time_to_connected(&self, elapsed: Duration) -> Result<(), anyhow::Error> {
+        let actor = self.type_.as_ref().to_owned();
+        let promise = gst::Promise::with_change_func(move |reply| {
+            let path = match reply {
+                Ok(Some(reply)) => CandidatePath::from_structure(reply),
+                _ => None,
+            };
+            let path = path.map_or("unknown", |path| path.label());
+            info!(actor = actor.as_str(), ?elapsed, path, "connected");
+            metrics::observe_time_to_connected(path, elapsed);
+        });
+
         self.webrtcbin
-            .emit_by_name("add-ice-candidate", &[&mlineindex, &candidate])
-            .expect("couldn't add ice candidate");
+            .emit_by_name("get-stats", &[&None::<gst::Pad>, &promise])?;
         Ok(())
     }
 
-    fn on_negotiation_needed(&self) -> Result<(), anyhow::Error> {
-        println!("starting negotiation");
+    /// Current `ConnectionQuality`, from a fresh `stats` snapshot.
+    pub async fn quality(&self) -> Result<ConnectionQuality, anyhow::Error> {
+        Ok(self.stats().await?.quality(&self.config.quality_thresholds))
+    }
+
+    /// Called from the streaming thread, the restart itself runs from the
+    /// pipeline's async call so the elements can change state.
+    fn on_source_eos(&self) {
+        // A short file ends every few seconds, only a source that doesn't
+        // produce anything between restarts is given up on
+        if self.source_flowing.swap(false, Ordering::SeqCst) {
+            self.empty_source_restarts.store(0, Ordering::SeqCst);
+        }
+        if self.empty_source_restarts.fetch_add(1, Ordering::SeqCst) >= SOURCE_RESTART_ATTEMPTS {
+            gst::element_error!(
+                self.pipeline,
+                gst::StreamError::Failed,
+                ("Video source keeps ending, giving up on restarting it")
+            );
+            return;
+        }
 
+        warn!(actor = self.type_.as_ref(), "video source ended, restarting it");
         let pl_clone = self.downgrade();
-        let promise = gst::Promise::with_change_func(move |reply| {
+        self.pipeline.call_async(move |_| {
             let pipeline = upgrade_weak!(pl_clone);
-
-            run! { async {
-                if let Err(err) = pipeline.on_offer_created(reply).await {
-                    gst::element_error!(
-                        pipeline.pipeline,
-                        gst::LibraryError::Failed,
-                        ("Failed to send SDP offer: {:?}", err)
-                    );
-                }
-            }}
+            if let Err(err) = pipeline.restart_source() {
+                gst::element_error!(
+                    pipeline.pipeline,
+                    gst::StreamError::Failed,
+                    ("Failed to restart video source: {:?}", err)
+                );
+            }
         });
+    }
 
-        self.webrtcbin
-            .emit_by_name("create-offer", &[&None::<gst::Structure>, &promise])
-            .expect("couldn't create offer");
+    /// Cycles every element upstream of the encoder through `Null`, leaving
+    /// the encoder, tee and webrtcbin running so the peer stays connected.
+    ///
+    /// A file starts over from the beginning. Live sources (v4l2, RTSP) only
+    /// resume once the device or camera is back, the peer sees a frozen
+    /// picture until then and a timestamp gap afterwards.
+    pub fn restart_source(&self) -> Result<(), anyhow::Error> {
+        let encoder = self
+            .pipeline
+            .by_name("encoder")
+            .ok_or_else(|| anyhow::anyhow!("there is no outgoing video"))?;
+
+        let mut elements = Vec::new();
+        let mut pad = encoder.static_pad("sink");
+        while let Some(peer) = pad.and_then(|pad| pad.peer()) {
+            let element = match peer.parent_element() {
+                Some(element) => element,
+                None => break,
+            };
+            pad = element.sink_pads().into_iter().next();
+            elements.push(element);
+        }
+
+        for element in &elements {
+            element.set_state(gst::State::Null)?;
+        }
+        for element in elements.iter().rev() {
+            element.sync_state_with_parent()?;
+        }
+        info!(actor = self.type_.as_ref(), elements = elements.len(), "video source restarted");
 
         Ok(())
     }
 
-    async fn on_offer_created(
-        &self,
-        reply: Result<Option<&gst::StructureRef>, gst::PromiseError>,
-    ) -> Result<(), anyhow::Error> {
-        let reply = match reply {
-            Ok(Some(reply)) => reply,
-            Ok(None) => {
-                bail!("Offer creation future got no response");
-            }
-            Err(err) => {
-                bail!("Offer creation future got error response: {:?}", err);
-            }
+    /// Replaces webrtcbin's `turn-server` with a fresh time-limited credential.
+    pub fn refresh_turn_credentials(&self) -> Result<(), anyhow::Error> {
+        let turn = match &self.config.ice.time_limited_turn {
+            Some(turn) => turn,
+            None => bail!("no time-limited TURN server configured"),
         };
+        let server = turn.server()?;
+        self.webrtcbin.set_property("turn-server", &ice::turn_uri(&server)?)?;
+        debug!(
+            actor = self.type_.as_ref(),
+            username = server.username.as_str(),
+            "renewed TURN credential"
+        );
 
-        let offer = reply
-            .value("offer")
-            .unwrap()
-            .get::<SessionDescription>()
-            .expect("Invalid argument");
-        self.webrtcbin
-            .emit_by_name("set-local-description", &[&offer, &None::<gst::Promise>])
-            .expect("couldn't set local description");
+        Ok(())
+    }
 
-        let sdp = offer.sdp().as_text().unwrap();
+    /// Current transport-wide congestion control figures, everything is bundled
+    /// so session 0 carries all media.
+    pub fn bandwidth_estimate(&self) -> Result<BandwidthEstimate, anyhow::Error> {
+        let rtpbin = self
+            .webrtcbin
+            .downcast_ref::<gst::Bin>()
+            .and_then(|bin| bin.by_name("rtpbin"))
+            .ok_or_else(|| anyhow::anyhow!("webrtcbin has no rtpbin"))?;
+        let session = rtpbin
+            .emit_by_name("get-session", &[&0u32])?
+            .and_then(|val| val.get::<glib::Object>().ok())
+            .ok_or_else(|| anyhow::anyhow!("no RTP session"))?;
+        let stats = session.property("twcc-stats")?.get::<gst::Structure>()?;
 
-        println!(
-            "sending SDP offer to peer: {}",
-            offer.sdp().as_text().unwrap()
-        );
+        Ok(BandwidthEstimate::from_structure(&stats))
+    }
 
-        self.handle_sdp(&SDPType::Offer, &sdp).await?;
+    /// Sends `msg` as a string message down the control data channel.
+    pub fn send_data(&self, msg: &str) -> Result<(), anyhow::Error> {
+        let data_channel = self.data_channel.lock().unwrap();
+        let data_channel = data_channel
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("control channel is not open"))?;
+        data_channel.emit_by_name("send-string", &[&msg])?;
 
         Ok(())
     }
 
-    async fn on_answer_created(
+    /// Sends `msg` to the remote peer through the `signaling_{type}` distributor.
+    fn signal(&self, msg: &JsonMsg) -> Result<(), anyhow::Error> {
+        let text = serde_json::to_string(msg)?;
+        debug!(actor = self.type_.as_ref(), %text, "sending signaling message");
+        Distributor::named(format!("signaling_{}", self.type_.as_ref()))
+            .tell_one(text)
+            .map_err(|err| anyhow::anyhow!("couldn't send signaling message: {:?}", err))
+    }
+
+    fn send_control_response(
         &self,
-        reply: Result<Option<&gst::StructureRef>, gst::PromiseError>,
+        response: &Envelope<ControlResponse>,
     ) -> Result<(), anyhow::Error> {
-        let reply = match reply {
-            Ok(Some(reply)) => reply,
-            Ok(None) => {
-                bail!("Answer creation future got no response");
+        self.send_data(&serde_json::to_string(response)?)
+    }
+
+    /// Must run before the first offer is created for the channel to be negotiated.
+    fn create_control_channel(&self) -> Result<(), anyhow::Error> {
+        let options = gst::Structure::builder("config")
+            .field("ordered", &true)
+            .build();
+        let data_channel = self
+            .webrtcbin
+            .emit_by_name("create-data-channel", &[&"control", &options])?
+            .and_then(|val| val.get::<glib::Object>().ok())
+            .ok_or_else(|| anyhow::anyhow!("couldn't create control data channel"))?;
+
+        data_channel.connect("on-open", false, |_| {
+            info!("control channel opened");
+            None
+        })?;
+
+        let pl_clone = self.downgrade();
+        data_channel.connect("on-close", false, move |_| {
+            info!("control channel closed");
+            let pipeline = upgrade_weak!(pl_clone, None);
+            pipeline.data_channel.lock().unwrap().take();
+            None
+        })?;
+
+        let pl_clone = self.downgrade();
+        let actor = Distributor::named(self.type_.as_ref());
+        let distributor = Distributor::named(format!("data_{}", self.type_.as_ref()));
+        data_channel.connect("on-message-string", false, move |values| {
+            let msg = match values[1].get::<Option<String>>() {
+                Ok(Some(msg)) => msg,
+                _ => return None,
+            };
+            match control::parse_request(&msg) {
+                Some(Ok(request)) => {
+                    if let Err(err) = actor.tell_one(request) {
+                        warn!(?err, "couldn't forward control request");
+                    }
+                }
+                Some(Err(response)) => {
+                    let pipeline = upgrade_weak!(pl_clone, None);
+                    if let Err(err) = pipeline.send_control_response(&response) {
+                        warn!(?err, "couldn't answer control request");
+                    }
+                }
+                None => {
+                    if let Err(err) = distributor.tell_one(msg) {
+                        warn!(?err, "couldn't forward control message");
+                    }
+                }
             }
-            Err(err) => {
-                bail!("Answer creation future got error response: {:?}", err);
+            None
+        })?;
+
+        *self.data_channel.lock().unwrap() = Some(data_channel);
+
+        Ok(())
+    }
+
+    /// Renegotiates with fresh ICE credentials, see `should_restart_ice`.
+    pub fn request_ice_restart(&self) -> Result<(), anyhow::Error> {
+        {
+            let mut last_ice_restart = self.last_ice_restart.lock().unwrap();
+            let now = Instant::now();
+            if !should_restart_ice(&self.type_, *last_ice_restart, now) {
+                debug!(
+                    actor = self.type_.as_ref(),
+                    since = ?last_ice_restart.map(|last| now - last),
+                    "not restarting ICE, leaving it to the offerer or restarted recently"
+                );
+                return Ok(());
+            }
+            *last_ice_restart = Some(now);
+        }
+
+        info!(actor = self.type_.as_ref(), "restarting ICE");
+        self.remote_candidates.lock().unwrap().clear();
+        let options = gst::Structure::builder("application/x-gst-webrtc")
+            .field("ice-restart", &true)
+            .build();
+        self.create_offer(Some(options))
+    }
+
+    /// Restarts ICE once the connection has been `Disconnected` for longer than
+    /// `ICE_DISCONNECTED_GRACE`, called from the actor's periodic tick.
+    pub fn check_ice_disconnected(&self) -> Result<(), anyhow::Error> {
+        {
+            let mut since = self.ice_disconnected_since.lock().unwrap();
+            if !disconnect_lingers(*since, Instant::now()) {
+                return Ok(());
+            }
+            *since = None;
+        }
+        self.request_ice_restart()
+    }
+
+    fn ice_connection_state(&self) -> Option<gst_webrtc::WebRTCICEConnectionState> {
+        self.webrtcbin
+            .property("ice-connection-state")
+            .ok()
+            .and_then(|val| val.get::<gst_webrtc::WebRTCICEConnectionState>().ok())
+    }
+
+    fn ice_gathering_state(&self) -> Option<gst_webrtc::WebRTCICEGatheringState> {
+        self.webrtcbin
+            .property("ice-gathering-state")
+            .ok()
+            .and_then(|val| val.get::<gst_webrtc::WebRTCICEGatheringState>().ok())
+    }
+
+    /// In `Full` mode the description is also held back, to be sent by
+    /// `send_gathered_description` once it is applied and gathering is done.
+    fn set_local_description(
+        &self,
+        type_: SDPType,
+        desc: &SessionDescription,
+    ) -> Result<(), anyhow::Error> {
+        let promise = match self.config.ice.mode {
+            IceMode::Trickle => None,
+            IceMode::Full => {
+                *self.pending_description.lock().unwrap() = Some(type_);
+                // Gathering may already be complete from an earlier round
+                let pl_clone = self.downgrade();
+                Some(gst::Promise::with_change_func(move |_| {
+                    let pipeline = upgrade_weak!(pl_clone);
+                    if pipeline.ice_gathering_state()
+                        == Some(gst_webrtc::WebRTCICEGatheringState::Complete)
+                    {
+                        pipeline.send_gathered_description();
+                    }
+                }))
             }
         };
 
-        let answer = reply
-            .value("answer")
-            .unwrap()
-            .get::<SessionDescription>()
-            .expect("Invalid argument");
         self.webrtcbin
-            .emit_by_name("set-local-description", &[&answer, &None::<gst::Promise>])
-            .expect("couldn't set local description for webrtcbin");
+            .emit_by_name("set-local-description", &[desc, &promise])?;
+        Ok(())
+    }
+
+    /// Sends the held back local description, which now has every candidate.
+    fn send_gathered_description(&self) {
+        let type_ = match self.pending_description.lock().unwrap().take() {
+            Some(type_) => type_,
+            None => return,
+        };
+        let desc = match self
+            .webrtcbin
+            .property("local-description")
+            .ok()
+            .and_then(|val| val.get::<Option<SessionDescription>>().ok().flatten())
+        {
+            Some(desc) => desc,
+            None => {
+                warn!(actor = self.type_.as_ref(), "no local description to send");
+                return;
+            }
+        };
+        debug!(actor = self.type_.as_ref(), "sending SDP with all candidates to peer");
+
+        if let Err(err) = self.send_local_description(type_, &desc.sdp()) {
+            gst::element_error!(
+                self.pipeline,
+                gst::LibraryError::Failed,
+                ("Failed to send SDP: {:?}", err)
+            );
+        }
+    }
+
+    /// Signals a local offer or answer to the peer, and prints it in the form
+    /// a console user pastes into the browser.
+    fn send_local_description(
+        &self,
+        type_: SDPType,
+        sdp: &gst_sdp::SDPMessageRef,
+    ) -> Result<(), anyhow::Error> {
+        println!("{}", utils::serialize(type_, sdp)?);
+
+        let name = signaling::sdp_type_name(type_)
+            .ok_or_else(|| anyhow::anyhow!("can't send a {} description", type_.to_str()))?;
+        self.signal(&JsonMsg::Sdp {
+            type_: name.to_owned(),
+            sdp: sdp.as_text()?,
+        })
+    }
+
+    /// Calls `func` with every RTP packet leaving the video payloader.
+    ///
+    /// The closure runs on the streaming thread and gets a borrowed, mapped view
+    /// of the packet, so it must be fast and copy out whatever it keeps.
+    /// Returns the probe id so the hook can be removed from the payloader's src pad.
+    pub fn on_outgoing_rtp<F>(&self, func: F) -> Result<gst::PadProbeId, anyhow::Error>
+    where
+        F: Fn(&gst_rtp::RTPBuffer<gst_rtp::rtp_buffer::Readable>) + Send + Sync + 'static,
+    {
+        let pad = self
+            .pipeline
+            .by_name("payloader")
+            .and_then(|payloader| payloader.static_pad("src"))
+            .ok_or_else(|| anyhow::anyhow!("couldn't find payloader src pad"))?;
+
+        pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+            move |_, info| {
+                let inspect = |buffer: &gst::BufferRef| {
+                    if let Ok(rtp) = gst_rtp::RTPBuffer::from_buffer_readable(buffer) {
+                        func(&rtp);
+                    }
+                };
+                match &info.data {
+                    Some(gst::PadProbeData::Buffer(buffer)) => inspect(buffer),
+                    Some(gst::PadProbeData::BufferList(list)) => list.iter().for_each(inspect),
+                    _ => (),
+                }
+                gst::PadProbeReturn::Ok
+            },
+        )
+        .ok_or_else(|| anyhow::anyhow!("couldn't add probe to payloader src pad"))
+    }
 
-        let sdp = answer.sdp().as_text().unwrap();
+    /// Tees the encoded video into a new file at `path` while the pipeline runs.
+    pub fn start_recording(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+        let mut recorder = self.recorder.lock().unwrap();
+        if recorder.is_some() {
+            bail!("already recording");
+        }
 
-        println!("sending SDP answer to peer: {}", sdp);
+        if self.pipeline.by_name("video_tee").is_none() {
+            bail!("there is no outgoing video to record");
+        }
+        *recorder = Some(self.attach_tee_branch(
+            "video_tee",
+            &format!(
+                "queue ! {} ! filesink name=record_file location=\"{}\"",
+                self.config.video_codec.muxer(),
+                path.display()
+            ),
+        )?);
+        drop(recorder);
 
-        self.handle_sdp(&SDPType::Answer, &sdp).await?;
+        // The file is only decodable from the first keyframe on
+        self.force_keyframe()?;
+        info!(actor = self.type_.as_ref(), path = %path.display(), "recording started");
 
         Ok(())
     }
-}
 
-fn main_loop(pipeline: WebRTCPipeline) -> Result<(), anyhow::Error> {
-    let bus = pipeline.pipeline.bus().unwrap();
+    /// Links a new bin from `description` to a fresh request pad of `tee`.
+    ///
+    /// On any failure the bin and the pad are taken out again, so the
+    /// pipeline is left as it was.
+    fn attach_tee_branch(
+        &self,
+        tee: &str,
+        description: &str,
+    ) -> Result<(gst::Bin, gst::Pad), anyhow::Error> {
+        let tee = self
+            .pipeline
+            .by_name(tee)
+            .ok_or_else(|| anyhow::anyhow!("couldn't find {}", tee))?;
+        let bin = gst::parse_bin_from_description(description, true)?;
+        self.pipeline.add(&bin)?;
+
+        let tee_pad = match tee.request_pad_simple("src_%u") {
+            Some(tee_pad) => tee_pad,
+            None => {
+                let _ = self.pipeline.remove(&bin);
+                bail!("couldn't request a pad from {}", tee.name());
+            }
+        };
+        let linked = tee_pad
+            .link(&bin.static_pad("sink").unwrap())
+            .map_err(anyhow::Error::from)
+            .and_then(|_| bin.sync_state_with_parent().map_err(anyhow::Error::from));
+        if let Err(err) = linked {
+            let _ = tee_pad.unlink(&bin.static_pad("sink").unwrap());
+            tee.release_request_pad(&tee_pad);
+            let _ = bin.set_state(gst::State::Null);
+            let _ = self.pipeline.remove(&bin);
+            return Err(err);
+        }
+
+        Ok((bin, tee_pad))
+    }
 
-    for msg in bus.iter_timed(gst::ClockTime::NONE) {
-        use gst::message::MessageView;
-        println!(".");
-        match msg.view() {
-            MessageView::Error(err) => bail!(
-                "Error from element {}: {} ({})",
-                err.src()
-                    .map(|s| String::from(s.path_string()))
-                    .unwrap_or_else(|| String::from("None")),
-                err.error(),
-                err.debug().unwrap_or_else(|| String::from("None")),
+    /// Finalizes the `start_recording` file, or else pushes EOS into the
+    /// `recording` branch so its current segment is finalized. The live
+    /// stream keeps flowing either way.
+    pub fn stop_recording(&self) -> Result<(), anyhow::Error> {
+        if let Some((bin, tee_pad)) = self.recorder.lock().unwrap().take() {
+            return self.remove_recorder(bin, tee_pad);
+        }
+
+        let queue = self
+            .pipeline
+            .by_name("record_queue")
+            .ok_or_else(|| anyhow::anyhow!("recording is disabled"))?;
+        let pad = queue.static_pad("sink").unwrap();
+        if !pad.send_event(gst::event::Eos::new()) {
+            bail!("couldn't send EOS to recorder");
+        }
+
+        Ok(())
+    }
+
+    /// Unlinks the branch from the tee with the tee pad blocked, drains it with
+    /// EOS so the muxer writes its headers, then removes it.
+    fn remove_recorder(&self, bin: gst::Bin, tee_pad: gst::Pad) -> Result<(), anyhow::Error> {
+        let tee = tee_pad
+            .parent_element()
+            .ok_or_else(|| anyhow::anyhow!("tee pad has no parent"))?;
+        let file_pad = bin
+            .by_name("record_file")
+            .and_then(|filesink| filesink.static_pad("sink"))
+            .ok_or_else(|| anyhow::anyhow!("couldn't find recorder filesink"))?;
+        let bin_sink = bin.static_pad("sink").unwrap();
+
+        let pipeline = self.pipeline.clone();
+        let released_pad = tee_pad.clone();
+        file_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            match &info.data {
+                Some(gst::PadProbeData::Event(event)) if event.type_() == gst::EventType::Eos => {
+                    let (bin, tee, tee_pad) = (bin.clone(), tee.clone(), released_pad.clone());
+                    pipeline.call_async(move |pipeline| {
+                        let _ = bin.set_state(gst::State::Null);
+                        let _ = pipeline.remove(&bin);
+                        tee.release_request_pad(&tee_pad);
+                        info!("recording finished");
+                    });
+                    gst::PadProbeReturn::Remove
+                }
+                _ => gst::PadProbeReturn::Ok,
+            }
+        });
+
+        tee_pad.add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, move |tee_pad, _| {
+            let _ = tee_pad.unlink(&bin_sink);
+            bin_sink.send_event(gst::event::Eos::new());
+            gst::PadProbeReturn::Remove
+        });
+
+        Ok(())
+    }
+
+    /// Adds or removes the local preview branch on `video_tee`. Removal blocks
+    /// the tee pad first so no buffer hits the branch while it is unlinked.
+    pub fn set_preview(&self, enabled: bool) -> Result<(), anyhow::Error> {
+        let mut preview = self.preview.lock().unwrap();
+        if enabled == preview.is_some() {
+            return Ok(());
+        }
+
+        if let Some((bin, tee_pad)) = preview.take() {
+            let tee = tee_pad
+                .parent_element()
+                .ok_or_else(|| anyhow::anyhow!("tee pad has no parent"))?;
+            let bin_sink = bin.static_pad("sink").unwrap();
+            let pipeline = self.pipeline.clone();
+            tee_pad.add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, move |tee_pad, _| {
+                let _ = tee_pad.unlink(&bin_sink);
+                let (bin, tee, tee_pad) = (bin.clone(), tee.clone(), tee_pad.clone());
+                pipeline.call_async(move |pipeline| {
+                    let _ = bin.set_state(gst::State::Null);
+                    let _ = pipeline.remove(&bin);
+                    tee.release_request_pad(&tee_pad);
+                });
+                gst::PadProbeReturn::Remove
+            });
+            info!(actor = self.type_.as_ref(), "local preview stopped");
+            return Ok(());
+        }
+
+        if self.pipeline.by_name("video_tee").is_none() {
+            bail!("there is no outgoing video to preview");
+        }
+        *preview = Some(self.attach_tee_branch("video_tee", PREVIEW_BRANCH)?);
+        drop(preview);
+
+        // decodebin can't start before the next keyframe, which comes on its own
+        // when the pipeline isn't running yet
+        if let Err(err) = self.force_keyframe() {
+            debug!(actor = self.type_.as_ref(), ?err, "couldn't force keyframe for preview");
+        }
+        info!(actor = self.type_.as_ref(), "local preview started");
+
+        Ok(())
+    }
+
+    /// Writes the current local and remote descriptions verbatim to
+    /// timestamped files in `sdp_dump_dir`.
+    pub fn dump_sdp(&self) -> Result<(), anyhow::Error> {
+        let dir = match &self.config.sdp_dump_dir {
+            Some(dir) => dir,
+            None => bail!("SDP dumping is disabled"),
+        };
+        std::fs::create_dir_all(dir)?;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        for property in ["local-description", "remote-description"] {
+            let desc = self
+                .webrtcbin
+                .property(property)?
+                .get::<Option<SessionDescription>>()?;
+            if let Some(desc) = desc {
+                let path = dir.join(format!(
+                    "{}-{}-{}.sdp",
+                    self.type_.as_ref(),
+                    timestamp,
+                    property
+                ));
+                std::fs::write(&path, desc.sdp().as_text()?)?;
+                info!(property, path = %path.display(), "dumped SDP");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the top-level pipeline has been observed reaching Playing.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Clock the pipeline is currently running on, if it has been selected yet.
+    pub fn clock(&self) -> Option<gst::Clock> {
+        self.pipeline.clock()
+    }
+
+    pub fn base_time(&self) -> Option<gst::ClockTime> {
+        self.pipeline.base_time()
+    }
+
+    /// Forces the pipeline onto `clock` instead of letting it pick one.
+    ///
+    /// Must be called before `run` to take effect on the first state change.
+    pub fn use_clock(&self, clock: &impl IsA<gst::Clock>) {
+        self.pipeline.use_clock(Some(clock));
+    }
+
+    /// Pins the base time so several pipelines share the same running time.
+    ///
+    /// Disables the start time as well, otherwise the pipeline recomputes its
+    /// base time on every transition to Playing.
+    pub fn set_base_time(&self, base_time: gst::ClockTime) {
+        self.pipeline.set_start_time(gst::ClockTime::NONE);
+        self.pipeline.set_base_time(base_time);
+    }
+
+    /// Slaves the pipeline to a `GstNetTimeProvider` published on `address:port`.
+    ///
+    /// For frame-synchronized output across machines, one process exposes its
+    /// clock with `gst_net::NetTimeProvider::new(&clock, None, port)` and every
+    /// pipeline (local or remote) calls this with that address and the same
+    /// `base_time`, before `run`. For PTP, build a `gst_net::PtpClock` instead
+    /// and hand it to `use_clock` directly.
+    pub fn use_net_clock(
+        &self,
+        address: &str,
+        port: i32,
+        base_time: gst::ClockTime,
+    ) -> Result<(), anyhow::Error> {
+        let clock = gst_net::NetClientClock::new(None, address, port, base_time);
+        clock
+            .wait_for_sync(Some(gst::ClockTime::from_seconds(5)))
+            .map_err(|_| anyhow::anyhow!("couldn't sync with net clock {}:{}", address, port))?;
+
+        self.use_clock(&clock);
+        self.set_base_time(base_time);
+
+        Ok(())
+    }
+
+    /// Takes an offer in the base64 `{type, sdp}` form printed to and pasted
+    /// from the console.
+    async fn handle_console_offer(&self, b64: &str) -> Result<(), anyhow::Error> {
+        let (type_, ret) = utils::deserialize(b64)?;
+        if type_ != SDPType::Offer {
+            bail!("expected an offer, got {:?}", type_);
+        }
+        self.handle_offer(ret).await
+    }
+
+    /// Applies a remote offer and sends the answer, failing when webrtcbin
+    /// refuses either.
+    async fn handle_offer(&self, ret: gst_sdp::SDPMessage) -> Result<(), anyhow::Error> {
+        let ret = utils::sanitize_sdp(
+            &ret,
+            self.config.video_codec,
+            &self.config.codec_preference,
+        )?;
+        self.check_h264_profile(&ret);
+        self.apply_remote_bandwidth(&ret)?;
+        let preferences = self.codec_preferences(&ret);
+
+        let offer = SessionDescription::new(SDPType::Offer, ret);
+        self.emit_with_promise("set-remote-description", &[&offer]).await?;
+        if let Err(err) = self.conn.remote_description_set() {
+            warn!(?err, "couldn't add held back ICE candidates");
+        }
+        if let Some(caps) = preferences {
+            if let Err(err) = self.set_codec_preferences(&caps) {
+                warn!(?err, "couldn't set codec preferences, keeping the default");
+            }
+        }
+
+        let reply = self.emit_with_promise("create-answer", &[&None::<gst::Structure>]).await?;
+        self.on_answer_created(reply).await
+    }
+
+    /// Emits the webrtcbin action `signal` with a promise appended to `args`
+    /// and waits for the reply, which webrtcbin carries an `error` in when it
+    /// refuses the operation.
+    async fn emit_with_promise(
+        &self,
+        signal: &str,
+        args: &[&dyn ToValue],
+    ) -> Result<Option<gst::Structure>, anyhow::Error> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let promise = gst::Promise::with_change_func(move |reply| {
+            let _ = tx.send(reply.map(|reply| reply.map(ToOwned::to_owned)));
+        });
+
+        let mut args = args.to_vec();
+        args.push(&promise);
+        self.webrtcbin.emit_by_name(signal, &args)?;
+
+        let reply = rx
+            .await?
+            .map_err(|err| anyhow::anyhow!("{} got error response: {:?}", signal, err))?;
+        if let Some(err) = reply
+            .as_ref()
+            .and_then(|reply| reply.get::<glib::Error>("error").ok())
+        {
+            bail!("webrtcbin refused {}: {}", signal, err);
+        }
+
+        Ok(reply)
+    }
+
+    async fn handle_sdp(&self, type_: &SDPType, sdp: &str) -> Result<(), anyhow::Error> {
+        match type_ {
+            &SDPType::Answer => {
+                debug!(actor = self.type_.as_ref(), %sdp, "received answer");
+
+                let ret = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("Failed to parse SDP answer"))?;
+
+                let ret = utils::sanitize_sdp(
+                    &ret,
+                    self.config.video_codec,
+                    &self.config.codec_preference,
+                )?;
+                self.check_h264_profile(&ret);
+                self.apply_remote_bandwidth(&ret)?;
+
+                let answer = SessionDescription::new(SDPType::Answer, ret);
+
+                self.emit_with_promise("set-remote-description", &[&answer]).await?;
+                self.conn.transition(ConnectionState::Answered)?;
+                self.conn.remote_description_set()?;
+
+                Ok(())
+            }
+            &SDPType::Offer => {
+                debug!(actor = self.type_.as_ref(), %sdp, "received offer");
+
+                let ret = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("Failed to parse SDP offer"))?;
+                self.handle_offer(ret).await
+            }
+            &SDPType::Pranswer => {
+                debug!(actor = self.type_.as_ref(), %sdp, "received provisional answer");
+
+                let ret = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("Failed to parse SDP provisional answer"))?;
+                let ret = utils::sanitize_sdp(
+                    &ret,
+                    self.config.video_codec,
+                    &self.config.codec_preference,
+                )?;
+                self.check_h264_profile(&ret);
+                self.apply_remote_bandwidth(&ret)?;
+
+                // The offer stays open until the final answer replaces this one
+                let pranswer = SessionDescription::new(SDPType::Pranswer, ret);
+                self.emit_with_promise("set-remote-description", &[&pranswer]).await?;
+                self.conn.remote_description_set()?;
+
+                Ok(())
+            }
+            &SDPType::Rollback => {
+                // Glare: both sides offered at once, drop our offer so theirs can
+                // be applied, or drop a remote offer the peer withdrew
+                let rollback =
+                    SessionDescription::new(SDPType::Rollback, gst_sdp::SDPMessage::new());
+                let signal = if self.conn.state() == ConnectionState::Offering {
+                    info!(actor = self.type_.as_ref(), "rolling back local offer");
+                    self.offer_pending.store(false, Ordering::SeqCst);
+                    self.conn.rollback()?;
+                    "set-local-description"
+                } else {
+                    info!(actor = self.type_.as_ref(), "rolling back remote offer");
+                    "set-remote-description"
+                };
+                self.webrtcbin.emit_by_name(signal, &[&rollback, &None::<gst::Promise>])?;
+
+                Ok(())
+            }
+            _ => bail!("unsupported SDP type \"{}\"", type_.to_str()),
+        }
+    }
+
+    /// The configured `codec_preference` narrowed to what `offer` has, `None`
+    /// leaves the choice to webrtcbin.
+    fn codec_preferences(&self, offer: &gst_sdp::SDPMessageRef) -> Option<gst::Caps> {
+        if self.config.codec_preference.is_empty() {
+            return None;
+        }
+
+        let caps = utils::codec_preferences(offer, &self.config.codec_preference);
+        match caps.as_ref().and_then(|caps| caps.structure(0)) {
+            None => debug!(
+                actor = self.type_.as_ref(),
+                preference = ?self.config.codec_preference,
+                "offer has none of the preferred codecs"
             ),
-            MessageView::Warning(warning) => {
-                println!("Warning: \"{}\"", warning.debug().unwrap());
+            Some(s) if self.config.transceiver_mode != TransceiverMode::RecvOnly => {
+                let name = s.get::<String>("encoding-name").unwrap_or_default();
+                if !name.eq_ignore_ascii_case(self.config.video_codec.encoding_name()) {
+                    warn!(
+                        actor = self.type_.as_ref(),
+                        %name,
+                        "preferred codec differs from the encoder, outgoing video won't play"
+                    );
+                }
             }
-            MessageView::Eos(..) => return Ok(()),
-            _ => (),
+            Some(_) => (),
         }
+        caps
     }
-    println!("break");
-    Ok(())
-}
 
-pub struct WebRTCBinActor;
+    fn set_codec_preferences(&self, caps: &gst::Caps) -> Result<(), anyhow::Error> {
+        let transceiver = self
+            .webrtcbin
+            .emit_by_name("get-transceiver", &[&0.to_value()])?
+            .and_then(|val| val.get::<glib::Object>().ok())
+            .ok_or_else(|| anyhow::anyhow!("no video transceiver"))?;
+        transceiver.set_property("codec-preferences", caps)?;
+        Ok(())
+    }
 
-impl WebRTCBinActor {
-    pub fn run(parent: SupervisorRef, type_: WebRTCBinActorType) {
-        parent
-            .supervisor(|s| {
-                s.with_restart_strategy(
-                    RestartStrategy::default()
-                        .with_restart_policy(RestartPolicy::Tries(5))
-                        .with_actor_restart_strategy(ActorRestartStrategy::Immediate),
-                )
-                .children(move |c| {
-                    c.with_distributor(Distributor::named(type_.as_ref()))
-                        .with_exec(move |ctx| main_fn(ctx, type_))
-                })
-            })
-            .expect("couldn't run Gstreamer actor");
+    fn on_incoming_stream(&self, pad: &gst::Pad) -> Result<(), anyhow::Error> {
+        if pad.direction() != gst::PadDirection::Src {
+            return Ok(());
+        }
+
+        let caps = pad.current_caps().unwrap();
+        let media = caps
+            .structure(0)
+            .and_then(|s| s.get::<String>("media").ok())
+            .unwrap_or_default();
+        // Follows the negotiated codec, which `codec_preference` may have changed
+        let video_codec = caps
+            .structure(0)
+            .and_then(|s| s.get::<String>("encoding-name").ok())
+            .and_then(|name| VideoCodec::from_encoding_name(&name))
+            .unwrap_or(self.config.video_codec);
+
+        if media == "video" && self.config.composite_incoming {
+            return self.composite_incoming_video(pad, video_codec);
+        }
+
+        let description = match media.as_str() {
+            "audio" => format!(
+                "{} ! rtpopusdepay ! opusdec ! audioconvert ! audioresample ! autoaudiosink",
+                self.config.latency.queue()
+            ),
+            "video" => format!(
+                "{} ! {} ! videoconvert ! videoscale ! {} ! {}",
+                self.config.latency.queue(),
+                video_codec.decoder(),
+                self.config.video.caps(),
+                self.config.incoming_video_sink
+            ),
+            _ => {
+                warn!(actor = self.type_.as_ref(), ?pad, "unknown pad, ignoring");
+                return Ok(());
+            }
+        };
+
+        let bin = gst::parse_bin_from_description(&description, true)?;
+        self.pipeline.add(&bin)?;
+        bin.sync_state_with_parent()?;
+
+        let sinkpad = bin.static_pad("sink").unwrap();
+        pad.link(&sinkpad)?;
+
+        Ok(())
     }
-}
 
-async fn main_fn(ctx: BastionContext, type_: WebRTCBinActorType) -> Result<(), ()> {
-    println!("WebRTCBin started");
-    gst::init().expect("couldn't initialize gstreamer");
-    let pipeline = WebRTCPipeline::init(&type_).expect("couldn't create webrtcbin pipeline");
-    pipeline.run().expect("couldn't start webrtc pipeline up");
-    let pl_clone = pipeline.downgrade();
-    blocking! {main_loop(pipeline)};
-    loop {
-        MessageHandler::new(ctx.recv().await?)
-            .on_tell(|sdp: String, _| {
-                run! { async {
-                    let pipeline = upgrade_weak!(pl_clone);
-                    pipeline
-                        .handle_sdp(&SDPType::Offer, "")
-                        .await
-                        .expect("couldn't handle sdp");
-                }}
-            })
-            .on_tell(|str: String, _| {
-                run! { async {
-                    let pipeline = upgrade_weak!(pl_clone);
-                    pipeline
-                        .handle_sdp(&SDPType::Answer, "")
-                        .await
-                        .expect("couldn't handle sdp");
-                }}
-            });
+    /// Decodes `pad` into a new cell of `incoming_compositor`.
+    fn composite_incoming_video(
+        &self,
+        pad: &gst::Pad,
+        video_codec: VideoCodec,
+    ) -> Result<(), anyhow::Error> {
+        let compositor = self.incoming_compositor()?;
+        let description = format!(
+            "{} ! {} ! videoconvert",
+            self.config.latency.queue(),
+            video_codec.decoder()
+        );
+        let bin = gst::parse_bin_from_description(&description, true)?;
+        self.pipeline.add(&bin)?;
+
+        let compositor_pad = match compositor.request_pad_simple("sink_%u") {
+            Some(compositor_pad) => compositor_pad,
+            None => {
+                let _ = self.pipeline.remove(&bin);
+                bail!("couldn't request a pad from incoming_compositor");
+            }
+        };
+        let linked = bin
+            .static_pad("src")
+            .unwrap()
+            .link(&compositor_pad)
+            .and_then(|_| pad.link(&bin.static_pad("sink").unwrap()))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| bin.sync_state_with_parent().map_err(anyhow::Error::from));
+        if let Err(err) = linked {
+            compositor.release_request_pad(&compositor_pad);
+            let _ = bin.set_state(gst::State::Null);
+            let _ = self.pipeline.remove(&bin);
+            return Err(err);
+        }
+
+        let mut videos = self.incoming_videos.lock().unwrap();
+        videos.push(IncomingVideo {
+            src: pad.clone(),
+            bin,
+            compositor_pad,
+        });
+        self.layout_incoming_videos(&videos)
+    }
+
+    /// The compositor shared by the incoming video streams, created on first use.
+    fn incoming_compositor(&self) -> Result<gst::Element, anyhow::Error> {
+        if let Some(compositor) = self.pipeline.by_name("incoming_compositor") {
+            return Ok(compositor);
+        }
+
+        let compositor = gst::ElementFactory::make("compositor", Some("incoming_compositor"))?;
+        compositor.set_property_from_str("background", "black")?;
+        let sink = gst::parse_bin_from_description(
+            &format!(
+                "videoconvert ! {} ! {}",
+                self.config.video.caps(),
+                self.config.incoming_video_sink
+            ),
+            true,
+        )?;
+        self.pipeline.add_many(&[&compositor, sink.upcast_ref()])?;
+        compositor.link(&sink)?;
+        sink.sync_state_with_parent()?;
+        compositor.sync_state_with_parent()?;
+
+        Ok(compositor)
+    }
+
+    /// Releases the compositor cell of an incoming stream that went away and
+    /// closes the gap in the grid.
+    fn remove_incoming_video(&self, pad: &gst::Pad) -> Result<(), anyhow::Error> {
+        let mut videos = self.incoming_videos.lock().unwrap();
+        let video = match videos.iter().position(|video| &video.src == pad) {
+            Some(idx) => videos.remove(idx),
+            None => return Ok(()),
+        };
+
+        video.bin.set_state(gst::State::Null)?;
+        self.pipeline.remove(&video.bin)?;
+        if let Some(compositor) = video.compositor_pad.parent_element() {
+            compositor.release_request_pad(&video.compositor_pad);
+        }
+
+        self.layout_incoming_videos(&videos)
+    }
+
+    /// Tiles the compositor pads row by row in the smallest square grid that
+    /// fits them all.
+    fn layout_incoming_videos(&self, videos: &[IncomingVideo]) -> Result<(), anyhow::Error> {
+        let count = videos.len() as u32;
+        if count == 0 {
+            return Ok(());
+        }
+        let columns = (1u32..).find(|columns| columns * columns >= count).unwrap();
+        let rows = (count + columns - 1) / columns;
+        let width = self.config.video.width / columns;
+        let height = self.config.video.height / rows;
+
+        for (idx, video) in (0u32..).zip(videos) {
+            let pad = &video.compositor_pad;
+            pad.set_property("xpos", &((idx % columns * width) as i32))?;
+            pad.set_property("ypos", &((idx / columns * height) as i32))?;
+            pad.set_property("width", &(width as i32))?;
+            pad.set_property("height", &(height as i32))?;
+        }
+
+        Ok(())
+    }
+
+    /// Picks the offered H264 `profile-level-id` the answer names.
+    ///
+    /// webrtcbin answers with the profile of the encoder's caps whatever the
+    /// remote offered, and browsers drop an H264 format whose profile they
+    /// didn't offer. The encoder can't switch once running, but its output
+    /// decodes with any profile `codec::compatible_h264_profile_level_id`
+    /// accepts, so the answer names that one instead.
+    fn check_h264_profile(&self, sdp: &gst_sdp::SDPMessageRef) {
+        if self.config.video_codec != VideoCodec::H264 {
+            return;
+        }
+
+        let offered = utils::h264_profile_level_ids(sdp);
+        let chosen = codec::compatible_h264_profile_level_id(&offered).map(String::from);
+        if chosen.is_none() && !offered.is_empty() {
+            let profiles = offered
+                .iter()
+                .map(|id| codec::h264_profile(id).unwrap_or("unknown"))
+                .collect::<Vec<_>>();
+            warn!(
+                actor = self.type_.as_ref(),
+                ?offered,
+                ?profiles,
+                "remote doesn't accept H264 {}, it may not decode the video",
+                H264_PROFILE
+            );
+        }
+        *self.h264_profile_level_id.lock().unwrap() = chosen;
+    }
+
+    /// Lowers the encoder bitrate to the remote's `b=AS`/`b=TIAS` limit, if it
+    /// has one below the configured bitrate.
+    fn apply_remote_bandwidth(&self, sdp: &gst_sdp::SDPMessageRef) -> Result<(), anyhow::Error> {
+        match utils::video_bandwidth_kbps(sdp) {
+            Some(limit) if limit < self.config.bitrate_kbps => {
+                info!(
+                    actor = self.type_.as_ref(),
+                    limit_kbps = limit,
+                    "remote limits video bandwidth"
+                );
+                self.set_bitrate(limit)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// An empty `candidate` is the end-of-candidates marker, sent in the same
+    /// form browsers use.
+    fn on_ice_candidate(&self, mlineindex: u32, candidate: String) -> Result<(), anyhow::Error> {
+        // The description carries them once gathering is complete
+        if self.config.ice.mode == IceMode::Full {
+            return Ok(());
+        }
+
+        if candidate.is_empty() {
+            debug!(actor = self.type_.as_ref(), mlineindex, "local end of candidates");
+        }
+
+        self.signal(&JsonMsg::Ice(IceCandidate {
+            candidate: Some(candidate),
+            sdp_mline_index: Some(mlineindex),
+            sdp_mid: self.mid_for_mline(mlineindex),
+        }))
+    }
+
+    /// Adds a remote candidate from its JSON form.
+    ///
+    /// webrtcbin only takes an mline index, so `sdpMid` is resolved against the
+    /// remote description first and `sdpMLineIndex` is the fallback when the
+    /// mid is absent or unknown. Malformed candidates are dropped, as are
+    /// repeats, which webrtcbin would otherwise accept and check twice.
+    pub fn handle_ice(&self, msg: &str) -> Result<(), anyhow::Error> {
+        self.add_remote_candidate(&serde_json::from_str(msg)?)
+    }
+
+    fn add_remote_candidate(&self, ice: &IceCandidate) -> Result<(), anyhow::Error> {
+        // Browsers signal end-of-candidates with an empty or null candidate
+        let candidate = ice.candidate.as_deref().unwrap_or_default();
+
+        let mlineindex = ice
+            .sdp_mid
+            .as_deref()
+            .and_then(|mid| self.remote_mline_for_mid(mid))
+            .or(ice.sdp_mline_index);
+
+        if candidate.is_empty() {
+            // Everything is bundled, so the first transport is the one that matters
+            let mlineindex = mlineindex.unwrap_or(0);
+            debug!(actor = self.type_.as_ref(), mlineindex, "remote end of candidates");
+            return self.conn.add_ice_candidate(mlineindex, "");
+        }
+
+        let mlineindex = mlineindex
+            .ok_or_else(|| anyhow::anyhow!("ICE message without usable sdpMid or sdpMLineIndex"))?;
+
+        if let Err(err) = utils::validate_candidate(candidate) {
+            warn!(actor = self.type_.as_ref(), ?err, "dropping malformed ICE candidate");
+            return Ok(());
+        }
+        let is_new = self
+            .remote_candidates
+            .lock()
+            .unwrap()
+            .insert((mlineindex, candidate.to_owned()));
+        if !is_new {
+            debug!(actor = self.type_.as_ref(), candidate, "dropping duplicate ICE candidate");
+            return Ok(());
+        }
+
+        self.conn.add_ice_candidate(mlineindex, candidate)
+    }
+
+    fn remote_mline_for_mid(&self, mid: &str) -> Option<u32> {
+        let desc = self
+            .webrtcbin
+            .property("remote-description")
+            .ok()?
+            .get::<Option<SessionDescription>>()
+            .ok()??;
+        utils::media_mids(&desc.sdp())
+            .iter()
+            .position(|m| m == mid)
+            .map(|idx| idx as u32)
+    }
+
+    /// Maps webrtcbin's mline index to the mid of the local description.
+    fn mid_for_mline(&self, mlineindex: u32) -> Option<String> {
+        utils::mid_for_mline(&self.mids.lock().unwrap(), mlineindex)
+    }
+
+    fn store_local_mids(&self, sdp: &gst_sdp::SDPMessageRef) {
+        *self.mids.lock().unwrap() = utils::media_mids(sdp);
+    }
+
+    fn on_negotiation_needed(&self) -> Result<(), anyhow::Error> {
+        self.renegotiate()
+    }
+
+    /// Starts a new offer/answer round, e.g. after `add_transceiver` or a
+    /// direction change.
+    ///
+    /// Skipped while an offer is still waiting for its answer, webrtcbin fires
+    /// `on-negotiation-needed` again once it is back to stable.
+    pub fn renegotiate(&self) -> Result<(), anyhow::Error> {
+        if self.conn.state() == ConnectionState::Offering
+            || self.offer_pending.swap(true, Ordering::SeqCst)
+        {
+            debug!(actor = self.type_.as_ref(), "offer already in flight");
+            return Ok(());
+        }
+
+        info!(actor = self.type_.as_ref(), "starting negotiation");
+        if let Err(err) = self.create_offer(None) {
+            self.offer_pending.store(false, Ordering::SeqCst);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Adds a transceiver to a running session and renegotiates it.
+    pub fn add_transceiver(
+        &self,
+        direction: gst_webrtc::WebRTCRTPTransceiverDirection,
+        caps: &gst::Caps,
+    ) -> Result<(), anyhow::Error> {
+        self.webrtcbin
+            .emit_by_name("add-transceiver", &[&direction, caps])?;
+
+        self.renegotiate()
+    }
+
+    /// Changes the direction of the video transceiver and renegotiates it.
+    pub fn set_video_direction(&self, mode: TransceiverMode) -> Result<(), anyhow::Error> {
+        let transceiver = self
+            .webrtcbin
+            .emit_by_name("get-transceiver", &[&0.to_value()])?
+            .and_then(|val| val.get::<glib::Object>().ok())
+            .ok_or_else(|| anyhow::anyhow!("no video transceiver"))?;
+        transceiver.set_property("direction", &mode.direction())?;
+
+        self.renegotiate()
+    }
+
+    fn create_offer(&self, options: Option<gst::Structure>) -> Result<(), anyhow::Error> {
+        let pl_clone = self.downgrade();
+        let promise = gst::Promise::with_change_func(move |reply| {
+            let pipeline = upgrade_weak!(pl_clone);
+
+            run! { async {
+                if let Err(err) = pipeline.on_offer_created(reply).await {
+                    gst::element_error!(
+                        pipeline.pipeline,
+                        gst::LibraryError::Failed,
+                        ("Failed to send SDP offer: {:?}", err)
+                    );
+                }
+            }}
+        });
+
+        self.webrtcbin
+            .emit_by_name("create-offer", &[&options, &promise])?;
+
+        Ok(())
+    }
+
+    async fn on_offer_created(
+        &self,
+        reply: Result<Option<&gst::StructureRef>, gst::PromiseError>,
+    ) -> Result<(), anyhow::Error> {
+        self.offer_pending.store(false, Ordering::SeqCst);
+        let reply = match reply {
+            Ok(Some(reply)) => reply,
+            Ok(None) => {
+                bail!("Offer creation future got no response");
+            }
+            Err(err) => {
+                bail!("Offer creation future got error response: {:?}", err);
+            }
+        };
+
+        let offer = reply
+            .value("offer")
+            .unwrap()
+            .get::<SessionDescription>()
+            .expect("Invalid argument");
+        let mut sdp = offer.sdp();
+        utils::ensure_lip_sync_group(&mut sdp)?;
+        utils::ensure_rtcp_mux(&mut sdp)?;
+        if self.config.simulcast {
+            utils::add_simulcast(&mut sdp, &SIMULCAST_RIDS)?;
+        }
+        self.store_local_mids(&sdp);
+        let offer = SessionDescription::new(SDPType::Offer, sdp);
+        self.set_local_description(SDPType::Offer, &offer)?;
+        self.conn.transition(ConnectionState::Offering)?;
+        if self.config.ice.mode == IceMode::Full {
+            debug!(actor = self.type_.as_ref(), "holding offer back until gathering completes");
+            return Ok(());
+        }
+
+        debug!(actor = self.type_.as_ref(), "sending SDP offer to peer");
+
+        self.send_local_description(SDPType::Offer, &offer.sdp())
+    }
+
+    async fn on_answer_created(&self, reply: Option<gst::Structure>) -> Result<(), anyhow::Error> {
+        let answer = reply
+            .ok_or_else(|| anyhow::anyhow!("Answer creation got no response"))?
+            .get::<SessionDescription>("answer")
+            .map_err(|err| anyhow::anyhow!("Invalid answer: {:?}", err))?;
+        let mut sdp = answer.sdp();
+        if let Some(id) = self.h264_profile_level_id.lock().unwrap().as_deref() {
+            sdp = utils::set_h264_profile_level_id(&sdp, id)?;
+        }
+        utils::ensure_lip_sync_group(&mut sdp)?;
+        utils::ensure_rtcp_mux(&mut sdp)?;
+        self.store_local_mids(&sdp);
+        let answer = SessionDescription::new(SDPType::Answer, sdp);
+        self.set_local_description(SDPType::Answer, &answer)?;
+        self.conn.transition(ConnectionState::Answered)?;
+
+        // Otherwise the peer waits up to keyframe-max-dist frames for a picture
+        if let Err(err) = self.force_keyframe() {
+            warn!(actor = self.type_.as_ref(), ?err, "couldn't force keyframe");
+        }
+        if self.config.ice.mode == IceMode::Full {
+            debug!(actor = self.type_.as_ref(), "holding answer back until gathering completes");
+            return Ok(());
+        }
+
+        debug!(actor = self.type_.as_ref(), "sending SDP answer to peer");
+
+        self.send_local_description(SDPType::Answer, &answer.sdp())
+    }
+}
+
+/// Operations the actor performs on its pipeline.
+pub trait MediaPipeline {
+    fn set_bitrate(&self, kbps: u32) -> Result<(), anyhow::Error>;
+    fn set_key_int_max(&self, frames: u32) -> Result<(), anyhow::Error>;
+    fn dump_sdp(&self) -> Result<(), anyhow::Error>;
+    fn stop_recording(&self) -> Result<(), anyhow::Error>;
+    fn request_ice_restart(&self) -> Result<(), anyhow::Error>;
+    fn force_keyframe(&self) -> Result<(), anyhow::Error>;
+    fn renegotiate(&self) -> Result<(), anyhow::Error>;
+    fn set_preview(&self, enabled: bool) -> Result<(), anyhow::Error>;
+    /// Applies a remote description in raw SDP form, answering offers.
+    async fn handle_sdp(&self, type_: &SDPType, sdp: &str) -> Result<(), anyhow::Error>;
+    /// Answers an offer in the base64 `{type, sdp}` form.
+    async fn handle_console_offer(&self, b64: &str) -> Result<(), anyhow::Error>;
+    fn add_remote_candidate(&self, ice: &IceCandidate) -> Result<(), anyhow::Error>;
+    /// Sends `msg` to the remote peer.
+    fn signal(&self, msg: &JsonMsg) -> Result<(), anyhow::Error>;
+}
+
+/// Everything `main_fn` needs on top of `MediaPipeline` to drive a pipeline.
+pub trait PipelineActor: MediaPipeline + Sized {
+    fn init(type_: &WebRTCBinActorType, config: &PipelineConfig) -> Result<Self, anyhow::Error>;
+    async fn run(&self) -> Result<(), anyhow::Error>;
+    fn bus(&self) -> gst::Bus;
+    /// The error to fail the actor with, if `msg` reports one.
+    fn handle_bus_message(&self, msg: &gst::Message) -> Option<PipelineError>;
+    fn bandwidth_estimate(&self) -> Result<BandwidthEstimate, anyhow::Error>;
+    fn refresh_turn_credentials(&self) -> Result<(), anyhow::Error>;
+    fn check_ice_disconnected(&self) -> Result<(), anyhow::Error>;
+    async fn stats(&self) -> Result<PeerStats, anyhow::Error>;
+    async fn quality(&self) -> Result<ConnectionQuality, anyhow::Error>;
+    async fn shutdown(&self) -> Result<(), anyhow::Error>;
+    fn start_recording(&self, path: &Path) -> Result<(), anyhow::Error>;
+    fn send_control_response(
+        &self,
+        response: &Envelope<ControlResponse>,
+    ) -> Result<(), anyhow::Error>;
+}
+
+impl MediaPipeline for WebRTCPipeline {
+    fn set_bitrate(&self, kbps: u32) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::set_bitrate(self, kbps)
+    }
+
+    fn set_key_int_max(&self, frames: u32) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::set_key_int_max(self, frames)
+    }
+
+    fn dump_sdp(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::dump_sdp(self)
+    }
+
+    fn stop_recording(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::stop_recording(self)
+    }
+
+    fn request_ice_restart(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::request_ice_restart(self)
+    }
+
+    fn force_keyframe(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::force_keyframe(self)
+    }
+
+    fn renegotiate(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::renegotiate(self)
+    }
+
+    fn set_preview(&self, enabled: bool) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::set_preview(self, enabled)
+    }
+
+    async fn handle_sdp(&self, type_: &SDPType, sdp: &str) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::handle_sdp(self, type_, sdp).await
+    }
+
+    async fn handle_console_offer(&self, b64: &str) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::handle_console_offer(self, b64).await
+    }
+
+    fn add_remote_candidate(&self, ice: &IceCandidate) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::add_remote_candidate(self, ice)
+    }
+
+    fn signal(&self, msg: &JsonMsg) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::signal(self, msg)
+    }
+}
+
+impl PipelineActor for WebRTCPipeline {
+    fn init(type_: &WebRTCBinActorType, config: &PipelineConfig) -> Result<Self, anyhow::Error> {
+        WebRTCPipeline::init(type_, config)
+    }
+
+    async fn run(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::run(self).await
+    }
+
+    fn bus(&self) -> gst::Bus {
+        self.pipeline.bus().unwrap()
+    }
+
+    fn handle_bus_message(&self, msg: &gst::Message) -> Option<PipelineError> {
+        handle_bus_message(self, msg)
+    }
+
+    fn bandwidth_estimate(&self) -> Result<BandwidthEstimate, anyhow::Error> {
+        WebRTCPipeline::bandwidth_estimate(self)
+    }
+
+    fn refresh_turn_credentials(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::refresh_turn_credentials(self)
+    }
+
+    fn check_ice_disconnected(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::check_ice_disconnected(self)
+    }
+
+    async fn stats(&self) -> Result<PeerStats, anyhow::Error> {
+        WebRTCPipeline::stats(self).await
+    }
+
+    async fn quality(&self) -> Result<ConnectionQuality, anyhow::Error> {
+        WebRTCPipeline::quality(self).await
+    }
+
+    async fn shutdown(&self) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::shutdown(self).await
+    }
+
+    fn start_recording(&self, path: &Path) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::start_recording(self, path)
+    }
+
+    fn send_control_response(
+        &self,
+        response: &Envelope<ControlResponse>,
+    ) -> Result<(), anyhow::Error> {
+        WebRTCPipeline::send_control_response(self, response)
+    }
+}
+
+fn pipeline_description(type_: &WebRTCBinActorType, config: &PipelineConfig) -> String {
+    let bitrate_kbps = config.clamp_bitrate(config.bitrate_kbps);
+    let mut extensions = Vec::new();
+    if config.bandwidth_estimation {
+        extensions.push((TWCC_EXTENSION_ID, TWCC_EXTENSION_URI));
+    }
+    if config.simulcast {
+        extensions.push((RID_EXTENSION_ID, RID_EXTENSION_URI));
+    }
+    let encoder =
+        config.video_codec.encoder("encoder", bitrate_kbps, config.key_int_max, &config.x264);
+    let payloader = config.video_codec.payloader("payloader", 96, &extensions);
+    let videorate = match config.video.framerate {
+        Some(framerate) => format!("videorate ! video/x-raw,framerate={}/1 ! ", framerate),
+        None => String::new(),
+    };
+    let source = config.video_source.description();
+    let video_queue = format!(
+        "queue name=video_queue leaky={} max-size-buffers=0 max-size-bytes=0 max-size-time={}",
+        if config.video_queue_leaky { "downstream" } else { "no" },
+        config.video_queue_max_time_ms * 1_000_000
+    );
+
+    let raw_video = format!("{} ! {}{},format=I420", source, videorate, config.video.caps());
+
+    // Both simulcast layers go through one rtpfunnel into the same transceiver
+    let outgoing = if config.simulcast {
+        format!(
+            "{} ! tee name=simulcast_tee ! 
+            queue ! {} ! tee name=video_tee ! queue ! {} ! 
+            rtpfunnel name=simulcast_funnel ! {} ! webrtcbin. 
+            simulcast_tee. ! queue ! videoscale ! video/x-raw,width={},height={} ! 
+            {} ! {} ! simulcast_funnel.",
+            raw_video,
+            encoder,
+            payloader,
+            video_queue,
+            config.video.width / 2,
+            config.video.height / 2,
+            config.video_codec.encoder(
+                "encoder_low",
+                bitrate_kbps / 4,
+                config.key_int_max,
+                &config.x264
+            ),
+            config.video_codec.payloader("payloader_low", 96, &extensions)
+        )
+    } else {
+        format!(
+            "{} ! {} ! tee name=video_tee ! queue ! {} ! {} ! webrtcbin.",
+            raw_video, encoder, payloader, video_queue
+        )
+    };
+
+    let mut description = match type_ {
+        _ if config.transceiver_mode == TransceiverMode::RecvOnly => {
+            "webrtcbin name=webrtcbin".to_owned()
+        }
+        _ => format!("webrtcbin name=webrtcbin {}", outgoing),
+    };
+
+    if let Some(recording) = &config.recording {
+        description.push_str(&format!(
+            " video_tee. ! queue name=record_queue ! splitmuxsink name=recorder 
+            max-size-time={} max-files={}",
+            recording.max_size_time_secs * 1_000_000_000,
+            recording.max_files
+        ));
+    }
+
+    if config.audio || config.audio_test_tone {
+        let wave = if config.audio_test_tone {
+            "sine freq=440"
+        } else {
+            "silence"
+        };
+        description.push_str(&format!(
+            " audiotestsrc wave={} is-live=true ! audioconvert ! audioresample ! 
+            queue ! {} ! rtpopuspay pt=97 ! 
+            application/x-rtp,media=audio,encoding-name=OPUS,payload=97 ! 
+            tee name=audio_tee ! queue ! webrtcbin.",
+            wave,
+            config.opus.encoder()
+        ));
+    }
+
+    description
+}
+
+/// Sets `rid` on the payloader's rtp-stream-id header extension.
+fn set_rid(payloader: &gst::Element, rid: &str) {
+    let extensions = match payloader
+        .property("extensions")
+        .ok()
+        .and_then(|val| val.get::<gst::Array>().ok())
+    {
+        Some(extensions) => extensions,
+        None => return,
+    };
+    for extension in extensions.as_slice() {
+        if let Ok(extension) = extension.get::<glib::Object>() {
+            if extension.has_property("rid", None) {
+                if let Err(err) = extension.set_property("rid", &rid) {
+                    warn!(rid, ?err, "couldn't set simulcast rid");
+                }
+            }
+        }
+    }
+}
+
+fn is_force_key_unit(event: &gst::Event) -> bool {
+    event.structure().map_or(false, |s| s.name() == "GstForceKeyUnit")
+}
+
+/// Posted to the actor's own distributor when the pipeline reports an error,
+/// the actor then fails so its supervisor restarts it.
+#[derive(Debug, Clone)]
+pub struct PipelineError {
+    pub element: String,
+    pub error: String,
+    pub debug: Option<String>,
+}
+
+fn handle_bus_message(pipeline: &WebRTCPipeline, msg: &gst::Message) -> Option<PipelineError> {
+    use gst::message::MessageView;
+
+    match msg.view() {
+        MessageView::Error(err) => {
+            return Some(PipelineError {
+                element: err
+                    .src()
+                    .map(|s| String::from(s.path_string()))
+                    .unwrap_or_else(|| String::from("None")),
+                error: err.error().to_string(),
+                debug: err.debug(),
+            });
+        }
+        MessageView::Warning(warning) => {
+            warn!(debug = ?warning.debug(), "{}", warning.error());
+        }
+        MessageView::StateChanged(state_changed) => {
+            if msg.src().as_ref() == Some(pipeline.pipeline.upcast_ref::<gst::Object>()) {
+                metrics::set_pipeline_state(pipeline.type_.as_ref(), state_changed.current());
+                let ready = state_changed.current() == gst::State::Playing;
+                let changed = pipeline.ready.swap(ready, Ordering::SeqCst) != ready;
+                metrics::set_pipeline_ready(pipeline.type_.as_ref(), pipeline.is_ready());
+                if changed {
+                    info!(
+                        old = ?state_changed.old(),
+                        current = ?state_changed.current(),
+                        "pipeline state changed"
+                    );
+                }
+            } else {
+                debug!(
+                    element = %msg.src().map(|s| String::from(s.path_string())).unwrap_or_default(),
+                    old = ?state_changed.old(),
+                    current = ?state_changed.current(),
+                    "element state changed"
+                );
+            }
+        }
+        MessageView::Eos(..) => debug!("end of stream"),
+        _ => (),
+    }
+
+    None
+}
+
+pub struct WebRTCBinActor;
+
+impl WebRTCBinActor {
+    /// Up to `max_restarts` restarts, waiting one more second before each.
+    pub fn restart_strategy(max_restarts: usize) -> RestartStrategy {
+        RestartStrategy::default()
+            .with_restart_policy(RestartPolicy::Tries(max_restarts))
+            .with_actor_restart_strategy(ActorRestartStrategy::LinearBackOff {
+                timeout: Duration::from_secs(1),
+            })
+    }
+
+    /// Every restart builds a new pipeline from `config`, the old one is set to
+    /// `Null` when it's dropped with the failed child. `gst::init` is safe to
+    /// call again on each start.
+    pub fn run(
+        parent: SupervisorRef,
+        type_: WebRTCBinActorType,
+        config: PipelineConfig,
+        restart_strategy: RestartStrategy,
+    ) {
+        parent
+            .supervisor(|s| {
+                s.with_restart_strategy(restart_strategy).children(move |c| {
+                    c.with_distributor(Distributor::named(type_.as_ref()))
+                        .with_exec(move |ctx| {
+                            let span =
+                                tracing::info_span!("webrtcbin", distributor = type_.as_ref());
+                            main_fn::<WebRTCPipeline>(ctx, type_, config.clone())
+                                .instrument(span)
+                        })
+                })
+            })
+            .expect("couldn't run Gstreamer actor");
+    }
+}
+
+async fn main_fn<P: PipelineActor>(
+    ctx: BastionContext,
+    type_: WebRTCBinActorType,
+    config: PipelineConfig,
+) -> Result<(), ()> {
+    info!("WebRTCBin started");
+    gst::init().expect("couldn't initialize gstreamer");
+    let pipeline = P::init(&type_, &config).expect("couldn't create webrtcbin pipeline");
+    if let Err(err) = pipeline.run().await {
+        error!(?err, "couldn't start webrtc pipeline up");
+        return Err(());
+    }
+    let pipeline = &pipeline;
+    let mut bus = pipeline.bus().stream();
+    let distributor = Distributor::named(type_.as_ref());
+    let bwe_distributor = Distributor::named(format!("bwe_{}", type_.as_ref()));
+    let mut tick_interval = tokio::time::interval(BWE_INTERVAL);
+    let turn_period = config
+        .ice
+        .time_limited_turn
+        .as_ref()
+        .map(|turn| turn.refresh_interval());
+    let mut turn_interval = tokio::time::interval(turn_period.unwrap_or(BWE_INTERVAL));
+    // The first tick is immediate, init already set a fresh credential
+    turn_interval.tick().await;
+
+    let mut shutdown = false;
+    let mut failed = false;
+    while !shutdown && !failed {
+        let msg = tokio::select! {
+            Some(msg) = bus.next() => {
+                if let Some(err) = pipeline.handle_bus_message(&msg) {
+                    if let Err(send_err) = distributor.tell_one(err) {
+                        error!(?send_err, "couldn't report pipeline error");
+                    }
+                }
+                continue;
+            }
+            _ = tick_interval.tick() => {
+                if let Err(err) = pipeline.check_ice_disconnected() {
+                    error!(actor = type_.as_ref(), ?err, "couldn't restart ICE");
+                }
+                if !config.bandwidth_estimation {
+                    continue;
+                }
+                match pipeline.bandwidth_estimate() {
+                    Ok(estimate) => {
+                        if let Err(err) = bwe_distributor.tell_one(estimate) {
+                            debug!(?err, "nobody listens for bandwidth estimates");
+                        }
+                    }
+                    Err(err) => debug!(?err, "no bandwidth estimate yet"),
+                }
+                continue;
+            }
+            _ = turn_interval.tick(), if turn_period.is_some() => {
+                if let Err(err) = pipeline.refresh_turn_credentials() {
+                    warn!(?err, "couldn't renew TURN credential");
+                }
+                continue;
+            }
+            msg = ctx.recv() => msg?,
+        };
+
+        MessageHandler::new(msg)
+            .on_tell(|err: PipelineError, _| {
+                error!(
+                    element = err.element.as_str(),
+                    debug = ?err.debug,
+                    "pipeline error: {}",
+                    err.error
+                );
+                failed = true;
+            })
+            .on_tell(|sdp: String, _| {
+                run! { async {
+                    if let Err(err) = pipeline.handle_console_offer(&sdp).await {
+                        error!(?err, "couldn't handle console offer");
+                    }
+                }}
+            })
+            .on_question(|question: &'static str, sender| match question {
+                "stats" => {
+                    run! { async {
+                        let answer = pipeline.stats().await.map_err(|err| err.to_string());
+                        let _ = sender.reply(answer);
+                    }}
+                }
+                "quality" => {
+                    run! { async {
+                        let answer = pipeline.quality().await.map_err(|err| err.to_string());
+                        let _ = sender.reply(answer);
+                    }}
+                }
+                "shutdown" => {
+                    shutdown = true;
+                    run! { async {
+                        if let Err(err) = pipeline.shutdown().await {
+                            error!(?err, "couldn't shut pipeline down");
+                        }
+                        let _ = sender.reply("stopped");
+                    }}
+                }
+                _ => (),
+            })
+            .on_tell(|msg: JsonMsg, _| {
+                run! { async {
+                    handle_signaling_message(pipeline, msg).await;
+                }}
+            })
+            .on_tell(|request: Envelope<ControlRequest>, _| {
+                run! { async {
+                    let handled = handle_control_request(pipeline, request.body);
+                    let body = tokio::time::timeout(CONTROL_REQUEST_TIMEOUT, handled)
+                        .await
+                        .unwrap_or_else(|_| {
+                            ControlResponse::error(ControlErrorCode::Timeout, "no answer in time")
+                        });
+                    let response = Envelope {
+                        request_id: request.request_id,
+                        body,
+                    };
+                    if let Err(err) = pipeline.send_control_response(&response) {
+                        warn!(
+                            request_id = request.request_id,
+                            ?err,
+                            "couldn't answer control request"
+                        );
+                    }
+                }}
+            })
+            .on_tell(|command: &'static str, _| {
+                if let Err(err) = handle_command(pipeline, command, None) {
+                    warn!(command, ?err, "couldn't handle command");
+                }
+            })
+            .on_tell(|(command, path): (&'static str, String), _| {
+                let result = match command {
+                    "start_recording" => pipeline.start_recording(Path::new(&path)),
+                    _ => Err(anyhow::anyhow!("unknown command")),
+                };
+                if let Err(err) = result {
+                    warn!(command, path = path.as_str(), ?err, "couldn't handle command");
+                }
+            })
+            .on_tell(|(command, arg): (&'static str, u32), _| {
+                if let Err(err) = handle_command(pipeline, command, Some(arg)) {
+                    warn!(command, arg, ?err, "couldn't handle command");
+                }
+            });
+    }
+
+    if failed {
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Only the offerer restarts ICE, with both restarting their offers would
+/// cross. The answerer gets fresh credentials with the offerer's restart
+/// offer. Restarts are at most once every `ICE_RESTART_INTERVAL`.
+fn should_restart_ice(type_: &WebRTCBinActorType, last: Option<Instant>, now: Instant) -> bool {
+    type_.is_offerer() && last.map_or(true, |last| now - last >= ICE_RESTART_INTERVAL)
+}
+
+/// Whether a `Disconnected` ICE connection seen at `since` has outlasted
+/// `ICE_DISCONNECTED_GRACE` instead of recovering.
+fn disconnect_lingers(since: Option<Instant>, now: Instant) -> bool {
+    since.map_or(false, |since| now - since >= ICE_DISCONNECTED_GRACE)
+}
+
+/// Dispatches the actor's control messages, kept apart from the GStreamer
+/// specifics so it can be driven by any `MediaPipeline`.
+fn handle_command(
+    pipeline: &impl MediaPipeline,
+    command: &str,
+    arg: Option<u32>,
+) -> Result<(), anyhow::Error> {
+    match (command, arg) {
+        ("dump_sdp", None) => pipeline.dump_sdp(),
+        ("stop_recording", None) => pipeline.stop_recording(),
+        ("ice_restart", None) => pipeline.request_ice_restart(),
+        ("force_keyframe", None) => pipeline.force_keyframe(),
+        ("renegotiate", None) => pipeline.renegotiate(),
+        ("start_preview", None) => pipeline.set_preview(true),
+        ("stop_preview", None) => pipeline.set_preview(false),
+        ("set_bitrate", Some(kbps)) => pipeline.set_bitrate(kbps),
+        ("set_key_int_max", Some(frames)) => pipeline.set_key_int_max(frames),
+        _ => bail!("unknown command \"{}\" ({:?})", command, arg),
+    }
+}
+
+/// Handles a message from the remote peer, telling it when that fails.
+async fn handle_signaling_message(pipeline: &impl MediaPipeline, msg: JsonMsg) {
+    if let Err(err) = handle_signaling(pipeline, msg).await {
+        warn!(?err, "rejecting signaling message");
+        let error = JsonMsg::Error {
+            code: signaling::ERROR_REJECTED,
+            reason: err.to_string(),
+        };
+        if let Err(err) = pipeline.signal(&error) {
+            warn!(?err, "couldn't report signaling error");
+        }
+    }
+}
+
+async fn handle_signaling(
+    pipeline: &impl MediaPipeline,
+    msg: JsonMsg,
+) -> Result<(), anyhow::Error> {
+    match msg {
+        JsonMsg::Sdp { type_, sdp } => {
+            let type_ = signaling::sdp_type(&type_)
+                .ok_or_else(|| anyhow::anyhow!("unknown SDP type \"{}\"", type_))?;
+            pipeline.handle_sdp(&type_, &sdp).await
+        }
+        JsonMsg::Ice(ice) => pipeline.add_remote_candidate(&ice),
+        JsonMsg::Error { code, reason } => {
+            error!(code, reason = reason.as_str(), "peer reported a signaling error");
+            Ok(())
+        }
+    }
+}
+
+async fn handle_control_request(
+    pipeline: &impl PipelineActor,
+    request: ControlRequest,
+) -> ControlResponse {
+    let result = match request {
+        ControlRequest::ListCameras => {
+            return ControlResponse::Cameras {
+                devices: control::list_cameras(),
+            }
+        }
+        ControlRequest::GetStats => {
+            return match pipeline.stats().await {
+                Ok(stats) => ControlResponse::Stats(stats),
+                Err(err) => ControlResponse::error(ControlErrorCode::Failed, err),
+            }
+        }
+        ControlRequest::SetBitrate { kbps } => pipeline.set_bitrate(kbps),
+        ControlRequest::ForceKeyframe => pipeline.force_keyframe(),
+        ControlRequest::IceRestart => pipeline.request_ice_restart(),
+    };
+
+    match result {
+        Ok(()) => ControlResponse::Ok,
+        Err(err) => ControlResponse::error(ControlErrorCode::Failed, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use bastion::Bastion;
+
+    use super::*;
+
+    /// Records the operations it is asked for instead of touching GStreamer.
+    #[derive(Default)]
+    struct MockPipeline {
+        calls: RefCell<Vec<String>>,
+        signaled: RefCell<Vec<JsonMsg>>,
+    }
+
+    impl MockPipeline {
+        fn record(&self, call: String) -> Result<(), anyhow::Error> {
+            self.calls.borrow_mut().push(call);
+            Ok(())
+        }
+    }
+
+    impl MediaPipeline for MockPipeline {
+        fn set_bitrate(&self, kbps: u32) -> Result<(), anyhow::Error> {
+            self.record(format!("set_bitrate {}", kbps))
+        }
+
+        fn set_key_int_max(&self, frames: u32) -> Result<(), anyhow::Error> {
+            self.record(format!("set_key_int_max {}", frames))
+        }
+
+        fn dump_sdp(&self) -> Result<(), anyhow::Error> {
+            self.record("dump_sdp".into())
+        }
+
+        fn stop_recording(&self) -> Result<(), anyhow::Error> {
+            self.record("stop_recording".into())
+        }
+
+        fn request_ice_restart(&self) -> Result<(), anyhow::Error> {
+            self.record("request_ice_restart".into())
+        }
+
+        fn force_keyframe(&self) -> Result<(), anyhow::Error> {
+            self.record("force_keyframe".into())
+        }
+
+        fn renegotiate(&self) -> Result<(), anyhow::Error> {
+            self.record("renegotiate".into())
+        }
+
+        fn set_preview(&self, enabled: bool) -> Result<(), anyhow::Error> {
+            self.record(format!("set_preview {}", enabled))
+        }
+
+        async fn handle_sdp(&self, type_: &SDPType, sdp: &str) -> Result<(), anyhow::Error> {
+            if sdp.is_empty() && *type_ != SDPType::Rollback {
+                bail!("empty SDP");
+            }
+            self.record(format!("handle_sdp {:?} {}", type_, sdp))
+        }
+
+        async fn handle_console_offer(&self, b64: &str) -> Result<(), anyhow::Error> {
+            self.record(format!("handle_console_offer {}", b64))
+        }
+
+        fn add_remote_candidate(&self, ice: &IceCandidate) -> Result<(), anyhow::Error> {
+            self.record(format!("add_remote_candidate {:?}", ice.candidate))
+        }
+
+        fn signal(&self, msg: &JsonMsg) -> Result<(), anyhow::Error> {
+            self.signaled.borrow_mut().push(msg.clone());
+            Ok(())
+        }
+    }
+
+    fn handle(pipeline: &MockPipeline, msg: JsonMsg) {
+        futures::executor::block_on(handle_signaling_message(pipeline, msg));
+    }
+
+    #[test]
+    fn signaling_reaches_the_pipeline() {
+        let pipeline = MockPipeline::default();
+        handle(
+            &pipeline,
+            JsonMsg::Sdp {
+                type_: "offer".into(),
+                sdp: "v=0".into(),
+            },
+        );
+        handle(
+            &pipeline,
+            JsonMsg::Ice(IceCandidate {
+                candidate: Some("candidate:1".into()),
+                ..Default::default()
+            }),
+        );
+
+        assert_eq!(
+            *pipeline.calls.borrow(),
+            vec![
+                "handle_sdp Offer v=0".to_owned(),
+                "add_remote_candidate Some(\"candidate:1\")".to_owned(),
+            ]
+        );
+        assert!(pipeline.signaled.borrow().is_empty());
+    }
+
+    #[test]
+    fn rollback_and_pranswer_reach_the_pipeline() {
+        let pipeline = MockPipeline::default();
+        for json in [
+            r#"{"sdp": {"type": "pranswer", "sdp": "v=0"}}"#,
+            r#"{"sdp": {"type": "rollback", "sdp": ""}}"#,
+        ] {
+            handle(&pipeline, serde_json::from_str(json).unwrap());
+        }
+
+        assert_eq!(
+            *pipeline.calls.borrow(),
+            vec!["handle_sdp Pranswer v=0".to_owned(), "handle_sdp Rollback ".to_owned()]
+        );
+        assert!(pipeline.signaled.borrow().is_empty());
+    }
+
+    #[test]
+    fn rejected_signaling_is_reported_to_the_peer() {
+        let pipeline = MockPipeline::default();
+        handle(
+            &pipeline,
+            JsonMsg::Sdp {
+                type_: "bogus".into(),
+                sdp: "v=0".into(),
+            },
+        );
+        handle(
+            &pipeline,
+            JsonMsg::Sdp {
+                type_: "answer".into(),
+                sdp: String::new(),
+            },
+        );
+
+        assert!(pipeline.calls.borrow().is_empty());
+        let signaled = pipeline.signaled.borrow();
+        assert_eq!(signaled.len(), 2);
+        for msg in signaled.iter() {
+            assert!(matches!(msg, JsonMsg::Error { code: signaling::ERROR_REJECTED, .. }));
+        }
+    }
+
+    #[test]
+    fn peer_errors_are_not_answered() {
+        let pipeline = MockPipeline::default();
+        handle(
+            &pipeline,
+            JsonMsg::Error {
+                code: signaling::ERROR_REJECTED,
+                reason: "bad answer".into(),
+            },
+        );
+
+        assert!(pipeline.calls.borrow().is_empty());
+        assert!(pipeline.signaled.borrow().is_empty());
+    }
+
+    #[test]
+    fn only_the_offerer_restarts_ice() {
+        let now = Instant::now();
+        assert!(should_restart_ice(&WebRTCBinActorType::Client, None, now));
+        assert!(!should_restart_ice(&WebRTCBinActorType::Server, None, now));
+        assert!(!should_restart_ice(
+            &WebRTCBinActorType::Server,
+            Some(now),
+            now + ICE_RESTART_INTERVAL
+        ));
+    }
+
+    #[test]
+    fn ice_restarts_are_rate_limited() {
+        let last = Instant::now();
+        let client = WebRTCBinActorType::Client;
+        assert!(!should_restart_ice(&client, Some(last), last));
+        assert!(!should_restart_ice(&client, Some(last), last + Duration::from_secs(1)));
+        assert!(should_restart_ice(&client, Some(last), last + ICE_RESTART_INTERVAL));
+    }
+
+    #[test]
+    fn commands_reach_the_pipeline() {
+        let pipeline = MockPipeline::default();
+        handle_command(&pipeline, "ice_restart", None).unwrap();
+        handle_command(&pipeline, "start_preview", None).unwrap();
+        handle_command(&pipeline, "set_bitrate", Some(500)).unwrap();
+        assert!(handle_command(&pipeline, "set_bitrate", None).is_err());
+        assert!(handle_command(&pipeline, "reboot", None).is_err());
+
+        assert_eq!(
+            *pipeline.calls.borrow(),
+            vec!["request_ice_restart", "set_preview true", "set_bitrate 500"]
+        );
+    }
+
+    const CANDIDATE: &str = "candidate:1 1 UDP 2013266431 10.0.0.1 5000 typ host";
+
+    /// Browser-like offer whose mids don't follow the mline indexes.
+    const REMOTE_OFFER: &str = "v=0\r\n\
+o=- 0 0 IN IP4 0.0.0.0\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE v a\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=ice-ufrag:remote\r\n\
+a=ice-pwd:remotepasswordremotepassword\r\n\
+a=fingerprint:sha-256 0B:30:55:7A:9F:C4:E9:0E:33:58:7D:A2:C7:EC:11:36:\
+5B:80:A5:CA:EF:14:39:5E:83:A8:CD:F2:17:3C:61:86\r\n\
+a=setup:actpass\r\n\
+a=sendrecv\r\n\
+a=rtcp-mux\r\n\
+a=mid:v\r\n\
+a=rtpmap:96 VP8/90000\r\n\
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=ice-ufrag:remote\r\n\
+a=ice-pwd:remotepasswordremotepassword\r\n\
+a=fingerprint:sha-256 0B:30:55:7A:9F:C4:E9:0E:33:58:7D:A2:C7:EC:11:36:\
+5B:80:A5:CA:EF:14:39:5E:83:A8:CD:F2:17:3C:61:86\r\n\
+a=setup:actpass\r\n\
+a=sendrecv\r\n\
+a=rtcp-mux\r\n\
+a=mid:a\r\n\
+a=rtpmap:111 OPUS/48000/2\r\n";
+
+    /// Just webrtcbin, which is enough to exercise candidates and descriptions.
+    fn recv_only_pipeline(config: PipelineConfig) -> WebRTCPipeline {
+        gst::init().unwrap();
+        let config = PipelineConfig {
+            transceiver_mode: TransceiverMode::RecvOnly,
+            ..config
+        };
+        WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap()
+    }
+
+    fn video_direction(pipeline: &WebRTCPipeline) -> gst_webrtc::WebRTCRTPTransceiverDirection {
+        let transceiver = pipeline
+            .webrtcbin
+            .emit_by_name("get-transceiver", &[&0.to_value()])
+            .unwrap()
+            .and_then(|val| val.get::<glib::Object>().ok())
+            .unwrap();
+        transceiver.property("direction").unwrap().get().unwrap()
+    }
+
+    #[test]
+    fn transceiver_direction_follows_the_mode() {
+        let pipeline = recv_only_pipeline(PipelineConfig::default());
+        assert_eq!(
+            video_direction(&pipeline),
+            gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly
+        );
+
+        let pipeline =
+            WebRTCPipeline::init(&WebRTCBinActorType::Server, &PipelineConfig::default()).unwrap();
+        assert_eq!(
+            video_direction(&pipeline),
+            gst_webrtc::WebRTCRTPTransceiverDirection::Sendrecv
+        );
+    }
+
+    #[test]
+    fn remote_candidates_are_added_once() {
+        let pipeline = recv_only_pipeline(PipelineConfig::default());
+        let ice = IceCandidate {
+            candidate: Some(CANDIDATE.into()),
+            sdp_mline_index: Some(0),
+            sdp_mid: None,
+        };
+        pipeline.add_remote_candidate(&ice).unwrap();
+        pipeline.add_remote_candidate(&ice).unwrap();
+        pipeline
+            .add_remote_candidate(&IceCandidate {
+                candidate: Some("candidate:garbage".into()),
+                ..ice.clone()
+            })
+            .unwrap();
+
+        // Without a remote description they are held back rather than handed to webrtcbin
+        assert_eq!(pipeline.conn.pending_candidates(), vec![(0, CANDIDATE.to_owned())]);
+    }
+
+    #[test]
+    fn remote_candidates_prefer_the_mid() {
+        let pipeline = recv_only_pipeline(PipelineConfig::default());
+        pipeline.pipeline.set_state(gst::State::Ready).unwrap();
+        let offer = SessionDescription::new(
+            SDPType::Offer,
+            gst_sdp::SDPMessage::parse_buffer(REMOTE_OFFER.as_bytes()).unwrap(),
+        );
+        let promise = gst::Promise::new();
+        pipeline
+            .webrtcbin
+            .emit_by_name("set-remote-description", &[&offer, &promise])
+            .unwrap();
+        promise.wait();
+
+        let ice = |sdp_mid: Option<&str>, sdp_mline_index: Option<u32>| IceCandidate {
+            candidate: Some(CANDIDATE.into()),
+            sdp_mline_index,
+            sdp_mid: sdp_mid.map(String::from),
+        };
+        pipeline.add_remote_candidate(&ice(Some("a"), Some(0))).unwrap();
+        // Unknown mids fall back to the mline index
+        pipeline.add_remote_candidate(&ice(Some("x"), Some(0))).unwrap();
+        assert!(pipeline.add_remote_candidate(&ice(Some("x"), None)).is_err());
+        assert!(pipeline.add_remote_candidate(&ice(None, None)).is_err());
+
+        assert_eq!(
+            pipeline.conn.pending_candidates(),
+            vec![(1, CANDIDATE.to_owned()), (0, CANDIDATE.to_owned())]
+        );
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn bitrate_is_clamped_to_the_ceiling() {
+        let config = PipelineConfig {
+            bitrate_kbps: 4000,
+            max_bitrate_kbps: Some(1500),
+            ..Default::default()
+        };
+        assert_eq!(config.clamp_bitrate(2000), 1500);
+        assert_eq!(config.clamp_bitrate(1500), 1500);
+        assert_eq!(config.clamp_bitrate(800), 800);
+        assert_eq!(PipelineConfig::default().clamp_bitrate(u32::MAX), u32::MAX);
+
+        // The initial bitrate is clamped as well, vp8enc takes it in bps
+        let description = WebRTCPipeline::launch_string(&WebRTCBinActorType::Server, &config);
+        assert!(description.contains("target-bitrate=1500000 "), "{}", description);
+    }
+
+    #[test]
+    fn set_bitrate_reaches_the_encoder() {
+        gst::init().unwrap();
+        let config = PipelineConfig {
+            bitrate_kbps: 800,
+            max_bitrate_kbps: Some(1500),
+            ..Default::default()
+        };
+
+        // vp8enc takes bps, x264enc kbps
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+        let encoder = pipeline.pipeline.by_name("encoder").unwrap();
+        let target_bitrate = || encoder.property("target-bitrate").unwrap().get::<i32>().unwrap();
+        handle_command(&pipeline, "set_bitrate", Some(1200)).unwrap();
+        assert_eq!(target_bitrate(), 1_200_000);
+        handle_command(&pipeline, "set_bitrate", Some(4000)).unwrap();
+        assert_eq!(target_bitrate(), 1_500_000);
+
+        let config = PipelineConfig {
+            video_codec: VideoCodec::H264,
+            ..config
+        };
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+        let encoder = pipeline.pipeline.by_name("encoder").unwrap();
+        let bitrate = || encoder.property("bitrate").unwrap().get::<u32>().unwrap();
+        handle_command(&pipeline, "set_bitrate", Some(1200)).unwrap();
+        assert_eq!(bitrate(), 1200);
+        handle_command(&pipeline, "set_bitrate", Some(4000)).unwrap();
+        assert_eq!(bitrate(), 1500);
+    }
+
+    #[test]
+    fn webrtcbin_uses_max_bundle() {
+        let pipeline = recv_only_pipeline(PipelineConfig::default());
+        let policy = pipeline
+            .webrtcbin
+            .property("bundle-policy")
+            .unwrap()
+            .get::<gst_webrtc::WebRTCBundlePolicy>()
+            .unwrap();
+        assert_eq!(policy, gst_webrtc::WebRTCBundlePolicy::MaxBundle);
+    }
+
+    #[test]
+    fn every_codec_parses() {
+        gst::init().unwrap();
+        for (video_codec, factory) in [
+            (VideoCodec::H264, "x264enc"),
+            (VideoCodec::Vp8, "vp8enc"),
+            (VideoCodec::Vp9, "vp9enc"),
+        ] {
+            let config = PipelineConfig {
+                video_codec,
+                ..Default::default()
+            };
+            let description = WebRTCPipeline::launch_string(&WebRTCBinActorType::Server, &config);
+            assert!(description.contains(&format!(
+                "encoding-name={},payload=96",
+                video_codec.encoding_name()
+            )));
+
+            let pipeline = gst::parse_launch(&description)
+                .unwrap()
+                .downcast::<gst::Pipeline>()
+                .unwrap();
+            let encoder = pipeline.by_name("encoder").unwrap();
+            assert_eq!(encoder.factory().unwrap().name().as_str(), factory);
+        }
+    }
+
+    #[test]
+    fn every_video_source_parses() {
+        gst::init().unwrap();
+        let missing = PathBuf::from("/nonexistent/video.mp4");
+        for (video_source, factory) in [
+            (VideoSource::TestPattern, "videotestsrc"),
+            (VideoSource::V4l2 { device: "/dev/video0".to_owned() }, "v4l2src"),
+            (VideoSource::Rtsp { url: "rtsp://127.0.0.1:8554/cam".to_owned() }, "rtspsrc"),
+            (VideoSource::File { path: missing.clone() }, "filesrc"),
+        ] {
+            let config = PipelineConfig {
+                video_source,
+                ..Default::default()
+            };
+            let description = WebRTCPipeline::launch_string(&WebRTCBinActorType::Server, &config);
+            let pipeline = gst::parse_launch(&description)
+                .unwrap()
+                .downcast::<gst::Pipeline>()
+                .unwrap();
+            let found = pipeline
+                .iterate_elements()
+                .into_iter()
+                .filter_map(Result::ok)
+                .any(|element| element.factory().map_or(false, |f| f.name() == factory));
+            assert!(found, "no {} in {}", factory, description);
+        }
+
+        // A missing file parses fine and only fails once filesrc opens it
+        let config = PipelineConfig {
+            video_source: VideoSource::File { path: missing },
+            ..Default::default()
+        };
+        assert!(config.video_source.validate().is_err());
+        let description = WebRTCPipeline::launch_string(&WebRTCBinActorType::Server, &config);
+        let pipeline = gst::parse_launch(&description).unwrap();
+        assert!(pipeline.set_state(gst::State::Paused).is_err());
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn key_int_max_reaches_the_encoder() {
+        gst::init().unwrap();
+        let keyframe_max_dist = |pipeline: &WebRTCPipeline| {
+            let encoder = pipeline.pipeline.by_name("encoder").unwrap();
+            encoder.property("keyframe-max-dist").unwrap().get::<i32>().unwrap()
+        };
+
+        let config = PipelineConfig {
+            key_int_max: 30,
+            ..Default::default()
+        };
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+        assert_eq!(keyframe_max_dist(&pipeline), 30);
+        pipeline.set_key_int_max(60).unwrap();
+        assert_eq!(keyframe_max_dist(&pipeline), 60);
+        assert!(pipeline.set_key_int_max(0).is_err());
+        assert_eq!(keyframe_max_dist(&pipeline), 60);
+
+        let config = PipelineConfig {
+            video_codec: VideoCodec::H264,
+            ..config
+        };
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+        let encoder = pipeline.pipeline.by_name("encoder").unwrap();
+        assert_eq!(encoder.property("key-int-max").unwrap().get::<i32>().unwrap(), 30);
+        // x264enc only reads it on startup
+        assert!(pipeline.set_key_int_max(60).is_err());
+
+        let config = PipelineConfig {
+            key_int_max: 0,
+            ..config
+        };
+        assert!(WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).is_err());
+    }
+
+    #[test]
+    fn every_opus_config_parses() {
+        gst::init().unwrap();
+        for (inband_fec, dtx) in [(false, false), (true, false), (false, true), (true, true)] {
+            let opus = OpusConfig {
+                bitrate: 32000,
+                inband_fec,
+                dtx,
+                packet_loss_percentage: 5,
+            };
+            let config = PipelineConfig {
+                audio: true,
+                opus,
+                ..Default::default()
+            };
+            let description = WebRTCPipeline::launch_string(&WebRTCBinActorType::Server, &config);
+            let pipeline = gst::parse_launch(&description)
+                .unwrap()
+                .downcast::<gst::Pipeline>()
+                .unwrap();
+
+            let encoder = pipeline.by_name("audio_encoder").unwrap();
+            let property = |name: &str| encoder.property(name).unwrap();
+            assert_eq!(property("bitrate").get::<i32>().unwrap(), 32000);
+            assert_eq!(property("inband-fec").get::<bool>().unwrap(), inband_fec);
+            assert_eq!(property("dtx").get::<bool>().unwrap(), dtx);
+            assert_eq!(property("packet-loss-percentage").get::<i32>().unwrap(), 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn full_ice_mode_embeds_the_candidates() {
+        gst::init().unwrap();
+        // Host candidates only, so gathering doesn't wait on the network
+        let config = PipelineConfig {
+            ice: IceConfig {
+                mode: IceMode::Full,
+                stun_servers: Vec::new(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Client, &config).unwrap();
+        pipeline.run().await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pipeline.ice_gathering_state() != Some(gst_webrtc::WebRTCICEGatheringState::Complete)
+            || pipeline.pending_description.lock().unwrap().is_some()
+        {
+            assert!(Instant::now() < deadline, "offer wasn't sent after gathering");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        let sdp = pipeline
+            .webrtcbin
+            .property("local-description")
+            .unwrap()
+            .get::<Option<SessionDescription>>()
+            .unwrap()
+            .unwrap()
+            .sdp()
+            .as_text()
+            .unwrap();
+        assert!(sdp.lines().any(|line| line.starts_with("a=candidate:")), "{}", sdp);
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn refused_descriptions_fail() {
+        let pipeline = recv_only_pipeline(PipelineConfig::default());
+        pipeline.pipeline.set_state(gst::State::Ready).unwrap();
+
+        // Nothing was offered, so webrtcbin has no use for an answer
+        let answered =
+            futures::executor::block_on(pipeline.handle_sdp(&SDPType::Answer, REMOTE_OFFER));
+        assert!(answered.is_err());
+        assert_eq!(pipeline.conn.state(), ConnectionState::New);
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn readiness_follows_the_pipeline_state() {
+        let pipeline = recv_only_pipeline(PipelineConfig::default());
+        let state_changed = |src: &gst::Element, old, current| {
+            gst::message::StateChanged::builder(old, current, gst::State::VoidPending)
+                .src(src)
+                .build()
+        };
+        let bin = pipeline.pipeline.upcast_ref::<gst::Element>();
+        assert!(!pipeline.is_ready());
+
+        handle_bus_message(&pipeline, &state_changed(bin, gst::State::Paused, gst::State::Playing));
+        assert!(pipeline.is_ready());
+        assert!(metrics::render().contains("webrtc_pipeline_ready{actor=\"server\"} 1"));
+
+        // Only the pipeline's own state counts, not its children's
+        let webrtcbin = &pipeline.webrtcbin;
+        handle_bus_message(
+            &pipeline,
+            &state_changed(webrtcbin, gst::State::Playing, gst::State::Paused),
+        );
+        assert!(pipeline.is_ready());
+
+        handle_bus_message(&pipeline, &state_changed(bin, gst::State::Playing, gst::State::Paused));
+        assert!(!pipeline.is_ready());
+        assert!(metrics::render().contains("webrtc_pipeline_ready{actor=\"server\"} 0"));
+        assert!(!metrics::is_healthy());
+    }
+
+    #[tokio::test]
+    async fn run_gives_up_on_a_broken_pipeline() {
+        gst::init().unwrap();
+        // v4l2src fails its state change right away without the device
+        let config = PipelineConfig {
+            video_source: VideoSource::V4l2 {
+                device: "/dev/does-not-exist".to_owned(),
+            },
+            ..Default::default()
+        };
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+
+        let started = Instant::now();
+        assert!(pipeline.run().await.is_err());
+        assert!(started.elapsed() >= PLAYING_RETRY_DELAY * (PLAYING_ATTEMPTS - 1));
+        assert_eq!(pipeline.pipeline.current_state(), gst::State::Null);
+    }
+
+    #[test]
+    fn only_lingering_disconnects_restart_ice() {
+        let since = Instant::now();
+        assert!(!disconnect_lingers(None, since + ICE_DISCONNECTED_GRACE));
+        assert!(!disconnect_lingers(Some(since), since + Duration::from_secs(1)));
+        assert!(disconnect_lingers(Some(since), since + ICE_DISCONNECTED_GRACE));
+    }
+
+    #[tokio::test]
+    async fn short_files_keep_looping() {
+        gst::init().unwrap();
+        let path = std::env::temp_dir().join(format!("short-{}.webm", std::process::id()));
+        let writer = gst::parse_launch(&format!(
+            "videotestsrc num-buffers=5 ! vp8enc ! webmmux ! filesink location=\"{}\"",
+            path.display()
+        ))
+        .unwrap();
+        writer.set_state(gst::State::Playing).unwrap();
+        let done = writer.bus().unwrap().timed_pop_filtered(
+            gst::ClockTime::from_seconds(10),
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        );
+        assert_eq!(done.map(|msg| msg.type_()), Some(gst::MessageType::Eos));
+        writer.set_state(gst::State::Null).unwrap();
+
+        let config = PipelineConfig {
+            video_source: VideoSource::File { path: path.clone() },
+            restart_source_on_eos: true,
+            ..Default::default()
+        };
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+        // The restart probe drops EOS at the encoder, count it right before
+        let eos_count = Arc::new(AtomicU32::new(0));
+        let counter = eos_count.clone();
+        let encoder_sink = pipeline.pipeline.by_name("encoder").unwrap().static_pad("sink");
+        encoder_sink.and_then(|pad| pad.peer()).unwrap().add_probe(
+            gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                    if event.type_() == gst::EventType::Eos {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+        pipeline.run().await.unwrap();
+
+        // Five frames end far more often than once every few seconds
+        let error = pipeline
+            .bus()
+            .timed_pop_filtered(gst::ClockTime::from_seconds(3), &[gst::MessageType::Error]);
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(error.is_none(), "{:?}", error);
+        assert!(eos_count.load(Ordering::SeqCst) > SOURCE_RESTART_ATTEMPTS);
+    }
+
+    #[test]
+    fn force_keyframe_reaches_the_encoder() {
+        gst::init().unwrap();
+        let pipeline =
+            WebRTCPipeline::init(&WebRTCBinActorType::Server, &PipelineConfig::default()).unwrap();
+        // Inactive pads refuse events
+        pipeline.pipeline.set_state(gst::State::Paused).unwrap();
+
+        // The encoder consumes the request, so it is only seen on its way in
+        let requested = Arc::new(AtomicBool::new(false));
+        let flag = requested.clone();
+        let encoder_src = pipeline.pipeline.by_name("encoder").unwrap().static_pad("src").unwrap();
+        encoder_src.add_probe(gst::PadProbeType::EVENT_UPSTREAM, move |_, info| {
+            if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                let structure = event.structure().filter(|s| s.name() == "GstForceKeyUnit");
+                if let Some(structure) = structure {
+                    assert_eq!(structure.get::<bool>("all-headers").ok(), Some(true));
+                    flag.store(true, Ordering::SeqCst);
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        handle_command(&pipeline, "force_keyframe", None).unwrap();
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+        assert!(requested.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn recordings_are_finalized() {
+        gst::init().unwrap();
+        let path = std::env::temp_dir().join(format!("recording-{}.mkv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pipeline =
+            WebRTCPipeline::init(&WebRTCBinActorType::Server, &PipelineConfig::default()).unwrap();
+        pipeline.run().await.unwrap();
+
+        pipeline.start_recording(&path).unwrap();
+        assert!(pipeline.start_recording(&path).is_err());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        pipeline.stop_recording().unwrap();
+
+        // The branch is only removed once EOS made it through the muxer
+        for _ in 0..50 {
+            if pipeline.pipeline.by_name("record_file").is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(pipeline.pipeline.by_name("record_file").is_none());
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+
+        let len = std::fs::metadata(&path).map(|metadata| metadata.len());
+        let _ = std::fs::remove_file(&path);
+        assert!(len.unwrap() > 0);
+        // The live branch is left alone
+        assert!(pipeline.pipeline.by_name("video_tee").is_some());
+    }
+
+    #[test]
+    fn connection_states_are_published() {
+        use gst_webrtc::WebRTCPeerConnectionState as State;
+
+        gst::init().unwrap();
+        assert_eq!(PeerConnectionState::from(State::Connecting), PeerConnectionState::Connecting);
+        assert_eq!(PeerConnectionState::from(State::Connected), PeerConnectionState::Connected);
+
+        Bastion::init();
+        Bastion::start();
+        let (tx, rx) = std::sync::mpsc::channel();
+        Bastion::children(|children| {
+            children
+                .with_distributor(Distributor::named("conn_state_client"))
+                .with_exec(move |ctx| {
+                    let tx = tx.clone();
+                    async move {
+                        while let Ok(msg) = ctx.recv().await {
+                            MessageHandler::new(msg).on_tell(
+                                |msg: (String, PeerConnectionState), _| {
+                                    let _ = tx.send(msg);
+                                },
+                            );
+                        }
+                        Ok(())
+                    }
+                })
+        })
+        .unwrap();
+
+        let pipeline =
+            WebRTCPipeline::init(&WebRTCBinActorType::Client, &PipelineConfig::default()).unwrap();
+        // The distributor only has a recipient once the child is up
+        let mut connecting = None;
+        for _ in 0..50 {
+            pipeline.on_connection_state(State::Connecting);
+            connecting = rx.recv_timeout(Duration::from_millis(100)).ok();
+            if connecting.is_some() {
+                break;
+            }
+        }
+        assert_eq!(connecting, Some(("client".to_owned(), PeerConnectionState::Connecting)));
+        while rx.try_recv().is_ok() {}
+
+        pipeline.on_connection_state(State::Connected);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ("client".to_owned(), PeerConnectionState::Connected)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_tee_branches_are_taken_out_again() {
+        gst::init().unwrap();
+        let pipeline =
+            WebRTCPipeline::init(&WebRTCBinActorType::Server, &PipelineConfig::default()).unwrap();
+        let tee = pipeline.pipeline.by_name("video_tee").unwrap();
+        let tee_pads = tee.src_pads().len();
+
+        // Raw audio can't link to encoded video
+        let branch = "audioconvert ! fakesink name=broken";
+        assert!(pipeline.attach_tee_branch("video_tee", branch).is_err());
+        assert!(pipeline.pipeline.by_name("broken").is_none());
+        assert_eq!(tee.src_pads().len(), tee_pads);
+
+        // Links, but filesink can't open the file once it follows the pipeline to Playing
+        pipeline.run().await.unwrap();
+        let path = Path::new("/nonexistent/recording.mkv");
+        assert!(pipeline.start_recording(path).is_err());
+        assert!(pipeline.recorder.lock().unwrap().is_none());
+        assert!(pipeline.pipeline.by_name("record_file").is_none());
+        assert_eq!(tee.src_pads().len(), tee_pads);
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn two_incoming_videos_sit_side_by_side() {
+        let config = PipelineConfig {
+            composite_incoming: true,
+            ..Default::default()
+        };
+        let pipeline = recv_only_pipeline(config);
+        let compositor = pipeline.incoming_compositor().unwrap();
+        let videos = (0..2)
+            .map(|_| IncomingVideo {
+                src: gst::Pad::new(None, gst::PadDirection::Src),
+                bin: gst::Bin::new(None),
+                compositor_pad: compositor.request_pad_simple("sink_%u").unwrap(),
+            })
+            .collect::<Vec<_>>();
+        pipeline.layout_incoming_videos(&videos).unwrap();
+
+        let width = pipeline.config.video.width as i32;
+        let height = pipeline.config.video.height as i32;
+        let property = |video: &IncomingVideo, name| {
+            video.compositor_pad.property(name).unwrap().get::<i32>().unwrap()
+        };
+        assert_eq!(property(&videos[0], "xpos"), 0);
+        assert_eq!(property(&videos[1], "xpos"), width / 2);
+        for video in &videos {
+            assert_eq!(property(video, "ypos"), 0);
+            assert_eq!(property(video, "width"), width / 2);
+            assert_eq!(property(video, "height"), height);
+        }
+    }
+
+    /// Waits for the offer `renegotiate` started to become the local description.
+    fn local_offer(pipeline: &WebRTCPipeline) -> gst_sdp::SDPMessage {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let description = pipeline
+                .webrtcbin
+                .property("local-description")
+                .unwrap()
+                .get::<Option<SessionDescription>>()
+                .unwrap();
+            if let Some(description) = description {
+                assert_eq!(description.type_(), SDPType::Offer);
+                return description.sdp();
+            }
+            assert!(Instant::now() < deadline, "no local offer");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn added_transceivers_get_an_mline() {
+        gst::init().unwrap();
+        let offer_mlines = |add: bool| {
+            let pipeline =
+                WebRTCPipeline::init(&WebRTCBinActorType::Server, &PipelineConfig::default())
+                    .unwrap();
+            // webrtcbin only runs its operations from Ready on
+            pipeline.pipeline.set_state(gst::State::Ready).unwrap();
+            if add {
+                let caps = gst::Caps::builder("application/x-rtp")
+                    .field("media", &"audio")
+                    .field("encoding-name", &"OPUS")
+                    .field("payload", &111)
+                    .field("clock-rate", &48000)
+                    .build();
+                let direction = gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly;
+                pipeline.add_transceiver(direction, &caps).unwrap();
+            } else {
+                pipeline.renegotiate().unwrap();
+            }
+            let mlines = local_offer(&pipeline).medias_len();
+            pipeline.pipeline.set_state(gst::State::Null).unwrap();
+            mlines
+        };
+
+        assert_eq!(offer_mlines(true), offer_mlines(false) + 1);
+    }
+
+    #[test]
+    fn local_preview_is_optional() {
+        gst::init().unwrap();
+        let bin = gst::parse_bin_from_description(PREVIEW_BRANCH, true).unwrap();
+        assert!(bin.static_pad("sink").is_some());
+
+        let pipeline =
+            WebRTCPipeline::init(&WebRTCBinActorType::Server, &PipelineConfig::default()).unwrap();
+        assert!(pipeline.preview.lock().unwrap().is_none());
+
+        let config = PipelineConfig {
+            local_preview: true,
+            ..Default::default()
+        };
+        let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+        let (bin, tee_pad) = pipeline.preview.lock().unwrap().clone().unwrap();
+        assert_eq!(bin.parent().as_ref(), Some(pipeline.pipeline.upcast_ref::<gst::Object>()));
+        assert!(tee_pad.is_linked());
+
+        pipeline.set_preview(false).unwrap();
+        assert!(pipeline.preview.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn latency_reaches_webrtcbin() {
+        let config = PipelineConfig {
+            latency: LatencyConfig {
+                latency_ms: 50,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pipeline = recv_only_pipeline(config);
+        // Older webrtcbin has no latency property, init leaves it alone then
+        if pipeline.webrtcbin.has_property("latency", None) {
+            let latency = pipeline.webrtcbin.property("latency").unwrap().get::<u32>().unwrap();
+            assert_eq!(latency, 50);
+        }
+    }
+
+    #[test]
+    fn rtx_offers_nack() {
+        gst::init().unwrap();
+        let offers_nack = |enable_rtx: bool| {
+            let config = PipelineConfig {
+                enable_rtx,
+                ..Default::default()
+            };
+            let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+            pipeline.pipeline.set_state(gst::State::Ready).unwrap();
+            pipeline.renegotiate().unwrap();
+            let offer = local_offer(&pipeline);
+            pipeline.pipeline.set_state(gst::State::Null).unwrap();
+
+            let video = offer.media(0).unwrap();
+            // "nack pli" is always there, generic NACK only with do-nack
+            video
+                .attributes()
+                .any(|attr| attr.key() == "rtcp-fb" && attr.value() == Some("96 nack"))
+        };
+
+        assert!(offers_nack(true));
+        assert!(!offers_nack(false));
+    }
+
+    #[tokio::test]
+    async fn run_after_shutdown_starts_a_new_connection() {
+        gst::init().unwrap();
+        let pipeline =
+            WebRTCPipeline::init(&WebRTCBinActorType::Client, &PipelineConfig::default()).unwrap();
+        pipeline.run().await.unwrap();
+        pipeline.shutdown().await.unwrap();
+        assert_eq!(pipeline.conn.state(), ConnectionState::Closed);
+
+        pipeline.run().await.unwrap();
+        // Closed is final, so even an offer already made again means it was reset
+        let state = pipeline.conn.state();
+        assert!(matches!(state, ConnectionState::New | ConnectionState::Offering), "{:?}", state);
+        pipeline.pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_doesnt_stall_the_tee() {
+        gst::init().unwrap();
+        // Buffers reaching a second branch on video_tee late enough for every
+        // queue in front of the stalled peer to have filled up
+        async fn flowing_after_stall(video_queue_leaky: bool) -> u32 {
+            let config = PipelineConfig {
+                video_queue_leaky,
+                ..Default::default()
+            };
+            let pipeline = WebRTCPipeline::init(&WebRTCBinActorType::Server, &config).unwrap();
+            let video_queue = pipeline.pipeline.by_name("video_queue").unwrap();
+            video_queue
+                .static_pad("src")
+                .unwrap()
+                .add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, |_, _| gst::PadProbeReturn::Ok);
+            let (bin, _) = pipeline.attach_tee_branch("video_tee", "queue ! fakesink").unwrap();
+            let buffers = Arc::new(AtomicU32::new(0));
+            let counter = buffers.clone();
+            bin.static_pad("sink").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                gst::PadProbeReturn::Ok
+            });
+
+            pipeline.run().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2500)).await;
+            let before = buffers.load(Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let flowing = buffers.load(Ordering::SeqCst) - before;
+            pipeline.pipeline.set_state(gst::State::Null).unwrap();
+            flowing
+        }
+
+        assert!(flowing_after_stall(true).await > 0);
+        assert_eq!(flowing_after_stall(false).await, 0);
+    }
+
+    #[test]
+    fn only_the_offerer_negotiates_on_its_own() {
+        gst::init().unwrap();
+        let config = PipelineConfig::default();
+        assert_eq!(
+            WebRTCPipeline::launch_string(&WebRTCBinActorType::Server, &config),
+            WebRTCPipeline::launch_string(&WebRTCBinActorType::Client, &config)
+        );
+
+        let negotiates = |type_: WebRTCBinActorType| {
+            let pipeline = WebRTCPipeline::init(&type_, &config).unwrap();
+            let signal = glib::subclass::SignalId::lookup(
+                "on-negotiation-needed",
+                pipeline.webrtcbin.type_(),
+            )
+            .unwrap();
+            glib::signal::signal_has_handler_pending(&pipeline.webrtcbin, signal, None, false)
+        };
+        assert!(negotiates(WebRTCBinActorType::Client));
+        assert!(!negotiates(WebRTCBinActorType::Server));
     }
 }